@@ -0,0 +1,142 @@
+// Full-precision token amounts
+use ethers::types::U256;
+use std::fmt;
+
+// Signed amount kept as a sign plus a full-width U256 magnitude, so any transfer value
+// and the difference of any two fit without truncation (an I256 cannot hold either)
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct SignedAmount {
+    negative: bool,
+    magnitude: U256,
+}
+
+impl SignedAmount {
+    fn new(negative: bool, magnitude: U256) -> Self {
+        // Keep a single representation of zero
+        SignedAmount { negative: negative && !magnitude.is_zero(), magnitude }
+    }
+
+    // inflow - outflow
+    pub fn net(inflow: U256, outflow: U256) -> Self {
+        if inflow >= outflow {
+            SignedAmount::new(false, inflow - outflow)
+        } else {
+            SignedAmount::new(true, outflow - inflow)
+        }
+    }
+
+    pub fn format_scaled(&self, decimals: u8) -> Option<String> {
+        let scaled = format_scaled(self.magnitude, decimals)?;
+        Some(if self.negative { format!("-{}", scaled) } else { scaled })
+    }
+}
+
+impl fmt::Display for SignedAmount {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.negative {
+            write!(f, "-{}", self.magnitude)
+        } else {
+            write!(f, "{}", self.magnitude)
+        }
+    }
+}
+
+// Running inflow and outflow totals of an exchange
+#[derive(Default)]
+pub struct Flow {
+    pub inflow: U256,
+    pub outflow: U256,
+}
+
+impl Flow {
+    pub fn add(&mut self, inflow: U256, outflow: U256) -> anyhow::Result<()> {
+        self.inflow = checked_sum(self.inflow, inflow)?;
+        self.outflow = checked_sum(self.outflow, outflow)?;
+        Ok(())
+    }
+
+    // Take back out what `add` put in
+    pub fn remove(&mut self, inflow: U256, outflow: U256) -> anyhow::Result<()> {
+        self.inflow = checked_sub(self.inflow, inflow)?;
+        self.outflow = checked_sub(self.outflow, outflow)?;
+        Ok(())
+    }
+
+    pub fn netflow(&self) -> SignedAmount {
+        SignedAmount::net(self.inflow, self.outflow)
+    }
+
+    // What was added after `earlier`, an older snapshot of the same running totals
+    pub fn since(&self, earlier: &Flow) -> Flow {
+        Flow {
+            inflow: self.inflow.saturating_sub(earlier.inflow),
+            outflow: self.outflow.saturating_sub(earlier.outflow),
+        }
+    }
+
+    // Raw base units, plus decimal-scaled values when the token's decimals are known
+    pub fn to_json(&self, decimals: Option<u8>) -> serde_json::Value {
+        let netflow = self.netflow();
+        serde_json::json!({
+            "inflow": self.inflow.to_string(),
+            "outflow": self.outflow.to_string(),
+            "netflow": netflow.to_string(),
+            "inflow_decimal": decimals.and_then(|d| format_scaled(self.inflow, d)),
+            "outflow_decimal": decimals.and_then(|d| format_scaled(self.outflow, d)),
+            "netflow_decimal": decimals.and_then(|d| netflow.format_scaled(d)),
+        })
+    }
+}
+
+// Parse a stored base-unit amount (decimal string)
+pub fn parse_amount(s: &str) -> anyhow::Result<U256> {
+    U256::from_dec_str(s).map_err(|e| anyhow::anyhow!("invalid amount {:?}: {}", s, e))
+}
+
+// Base units scaled by the token's decimals, e.g. 1500000000000000000 -> "1.500000000000000000"
+pub fn format_scaled(amount: U256, decimals: u8) -> Option<String> {
+    ethers::utils::format_units(amount, decimals as u32).ok()
+}
+
+// Sum amounts, failing instead of wrapping on overflow
+pub fn checked_sum(total: U256, amount: U256) -> anyhow::Result<U256> {
+    total.checked_add(amount).ok_or_else(|| anyhow::anyhow!("amount overflow adding {} to {}", amount, total))
+}
+
+// Take an amount back out of a total it was added to, failing instead of wrapping below zero
+pub fn checked_sub(total: U256, amount: U256) -> anyhow::Result<U256> {
+    total.checked_sub(amount).ok_or_else(|| anyhow::anyhow!("amount underflow subtracting {} from {}", amount, total))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn net_spans_the_full_u256_range() {
+        assert_eq!(SignedAmount::net(U256::MAX, U256::zero()).to_string(), U256::MAX.to_string());
+        assert_eq!(SignedAmount::net(U256::zero(), U256::MAX).to_string(), format!("-{}", U256::MAX));
+        assert_eq!(SignedAmount::net(U256::MAX, U256::MAX - 1).to_string(), "1");
+        assert_eq!(SignedAmount::net(U256::MAX - 1, U256::MAX).to_string(), "-1");
+    }
+
+    #[test]
+    fn zero_has_no_sign() {
+        assert_eq!(SignedAmount::net(U256::MAX, U256::MAX), SignedAmount::default());
+        assert_eq!(SignedAmount::net(U256::zero(), U256::zero()).to_string(), "0");
+    }
+
+    #[test]
+    fn scales_negative_amounts() {
+        let amount = SignedAmount::net(U256::zero(), U256::from(1_500_000u64));
+        assert_eq!(amount.format_scaled(6).as_deref(), Some("-1.500000"));
+    }
+
+    #[test]
+    fn totals_fail_instead_of_wrapping() {
+        let mut flow = Flow { inflow: U256::MAX, outflow: U256::zero() };
+        assert!(flow.add(U256::one(), U256::zero()).is_err());
+        assert!(flow.remove(U256::zero(), U256::one()).is_err());
+        assert!(parse_amount("-1").is_err());
+    }
+}
@@ -0,0 +1,171 @@
+// config.toml schema; addresses are parsed and checksum-validated at load time
+use ethers::types::Address;
+use ethers::utils::to_checksum;
+use serde::{Deserialize, Deserializer};
+use std::collections::BTreeMap;
+use std::fs;
+
+#[derive(Deserialize)]
+pub struct Config {
+    #[serde(default)]
+    pub storage: StorageConfig,
+    pub chains: Vec<Chain>,
+}
+
+// Where netflow is persisted; SQLite at netflow.db unless configured otherwise
+#[derive(Deserialize)]
+#[serde(tag = "backend", rename_all = "lowercase")]
+pub enum StorageConfig {
+    Sqlite {
+        #[serde(default = "default_sqlite_path")]
+        path: String,
+    },
+    Postgres {
+        url: String,
+    },
+}
+
+impl Default for StorageConfig {
+    fn default() -> Self {
+        StorageConfig::Sqlite { path: default_sqlite_path() }
+    }
+}
+
+fn default_sqlite_path() -> String { "netflow.db".to_string() }
+
+// One indexed network, each with its own RPC, tokens and exchange addresses
+#[derive(Clone, Deserialize)]
+pub struct Chain {
+    pub name: String,
+    // Checked against eth_chainId on connect and stored on every row
+    pub chain_id: u64,
+    pub rpc_url: String,
+    // Tried in order after `rpc_url` whenever the current connection fails
+    #[serde(default)]
+    pub fallback_rpc_urls: Vec<String>,
+    // First block to backfill from before going live; omit to only follow new blocks
+    #[serde(default)]
+    pub start_block: Option<u64>,
+    // Number of blocks requested per eth_getLogs page during backfill
+    #[serde(default = "default_backfill_batch_size")]
+    pub backfill_batch_size: u64,
+    // Blocks on top of a transfer before its netflow counts as finalized
+    #[serde(default = "default_confirmations")]
+    pub confirmations: u64,
+    // Use the node's `finalized` block tag instead of a fixed confirmation depth
+    #[serde(default)]
+    pub use_finalized_tag: bool,
+    // `subscribe` needs a WebSocket RPC; `poll` works with plain HTTP endpoints
+    #[serde(default)]
+    pub ingestion: IngestionMode,
+    // Delay between eth_getLogs polls in `poll` mode
+    #[serde(default = "default_poll_interval_ms")]
+    pub poll_interval_ms: u64,
+    pub tokens: Vec<Token>,
+    // Exchange name -> wallet addresses it controls
+    #[serde(deserialize_with = "exchange_addresses")]
+    pub exchanges: BTreeMap<String, Vec<Address>>,
+    // Count moves between wallets of the same exchange (they net to zero either way)
+    #[serde(default)]
+    pub count_internal_transfers: bool,
+}
+
+#[derive(Clone, Copy, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum IngestionMode {
+    #[default]
+    Subscribe,
+    Poll,
+}
+
+fn default_backfill_batch_size() -> u64 { 2_000 }
+fn default_confirmations() -> u64 { 128 }
+fn default_poll_interval_ms() -> u64 { 2_000 }
+
+#[derive(Clone, Deserialize)]
+pub struct Token {
+    #[serde(deserialize_with = "checksummed_address")]
+    pub address: Address,
+}
+
+// Parse errors carry the line and column of the offending entry
+pub fn load_config(path: &str) -> anyhow::Result<Config> {
+    let text = fs::read_to_string(path).map_err(|e| anyhow::anyhow!("cannot read {}: {}", path, e))?;
+    toml::from_str(&text).map_err(|e| anyhow::anyhow!("invalid {}: {}", path, e))
+}
+
+// "0x" + 40 hex digits. All-lowercase and all-uppercase spellings carry no checksum;
+// mixed case must match EIP-55 exactly, so a mistyped address is rejected, not ignored.
+pub fn parse_address(raw: &str) -> Result<Address, String> {
+    let digits = raw.strip_prefix("0x")
+        .filter(|d| d.len() == 40 && d.chars().all(|c| c.is_ascii_hexdigit()))
+        .ok_or_else(|| format!("{:?} is not a 0x-prefixed 20-byte hex address", raw))?;
+    let address: Address = raw.parse().map_err(|e| format!("invalid address {:?}: {}", raw, e))?;
+
+    let has_lower = digits.chars().any(|c| c.is_ascii_lowercase());
+    let has_upper = digits.chars().any(|c| c.is_ascii_uppercase());
+    if has_lower && has_upper {
+        let checksummed = to_checksum(&address, None);
+        if checksummed != raw {
+            return Err(format!("bad EIP-55 checksum in {} (expected {})", raw, checksummed));
+        }
+    }
+    Ok(address)
+}
+
+fn checksummed_address<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Address, D::Error> {
+    let raw = String::deserialize(deserializer)?;
+    parse_address(&raw).map_err(serde::de::Error::custom)
+}
+
+// Wrapper so each list entry is validated on its own and errors point at that entry
+#[derive(Deserialize)]
+struct ConfigAddress(#[serde(deserialize_with = "checksummed_address")] Address);
+
+fn exchange_addresses<'de, D: Deserializer<'de>>(deserializer: D) -> Result<BTreeMap<String, Vec<Address>>, D::Error> {
+    let exchanges = BTreeMap::<String, Vec<ConfigAddress>>::deserialize(deserializer)?;
+    Ok(exchanges.into_iter()
+        .map(|(name, addresses)| (name, addresses.into_iter().map(|a| a.0).collect()))
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Test vector from EIP-55
+    const CHECKSUMMED: &str = "0x5aAeb6053F3E94C9b9A09f33669435E7Ef1BeAed";
+
+    fn expected() -> Address {
+        CHECKSUMMED.parse().unwrap()
+    }
+
+    #[test]
+    fn accepts_single_case_without_checksum() {
+        assert_eq!(parse_address(&CHECKSUMMED.to_lowercase()), Ok(expected()));
+        assert_eq!(parse_address(&format!("0x{}", CHECKSUMMED[2..].to_uppercase())), Ok(expected()));
+    }
+
+    #[test]
+    fn accepts_valid_checksum() {
+        assert_eq!(parse_address(CHECKSUMMED), Ok(expected()));
+    }
+
+    #[test]
+    fn rejects_bad_checksum() {
+        let mistyped = "0x5aAeb6053F3E94C9b9A09f33669435E7Ef1BeAeD";
+        let error = parse_address(mistyped).unwrap_err();
+        assert!(error.contains("EIP-55"), "{}", error);
+    }
+
+    #[test]
+    fn rejects_wrong_length() {
+        assert!(parse_address(&CHECKSUMMED[..41]).is_err());
+        assert!(parse_address(&format!("{}00", CHECKSUMMED)).is_err());
+    }
+
+    #[test]
+    fn rejects_missing_prefix() {
+        assert!(parse_address(&CHECKSUMMED[2..]).is_err());
+    }
+}
@@ -0,0 +1,65 @@
+// API errors, reported to clients as JSON with a matching status code
+use std::convert::Infallible;
+use warp::http::StatusCode;
+use warp::{Rejection, Reply};
+
+#[derive(Debug)]
+pub enum ApiError {
+    // A query parameter or header that can't be used
+    BadRequest(String),
+    // Nothing stored for the request
+    NotFound(String),
+    // Storage could not be reached or queried
+    Unavailable(anyhow::Error),
+}
+
+impl ApiError {
+    pub fn bad_request(error: anyhow::Error) -> Self {
+        ApiError::BadRequest(format!("{:#}", error))
+    }
+}
+
+impl warp::reject::Reject for ApiError {}
+
+// Failures from storage calls; parameter errors are mapped with `bad_request` instead
+impl From<anyhow::Error> for ApiError {
+    fn from(error: anyhow::Error) -> Self {
+        ApiError::Unavailable(error)
+    }
+}
+
+fn json_error(status: StatusCode, message: String) -> warp::reply::WithStatus<warp::reply::Json> {
+    let body = serde_json::json!({ "status": status.as_u16(), "error": message });
+    warp::reply::with_status(warp::reply::json(&body), status)
+}
+
+// Turn every rejection, ours or warp's, into a JSON error response
+pub async fn handle_rejection(rejection: Rejection) -> Result<impl Reply, Infallible> {
+    let (status, message) = if let Some(error) = rejection.find::<ApiError>() {
+        match error {
+            ApiError::BadRequest(message) => (StatusCode::BAD_REQUEST, message.clone()),
+            ApiError::NotFound(message) => (StatusCode::NOT_FOUND, message.clone()),
+            ApiError::Unavailable(e) => {
+                println!("❌ Storage error: {:#}", e);
+                (StatusCode::SERVICE_UNAVAILABLE, "storage unavailable".to_string())
+            }
+        }
+    } else if rejection.is_not_found() {
+        (StatusCode::NOT_FOUND, "not found".to_string())
+    } else if let Some(e) = rejection.find::<warp::reject::InvalidQuery>() {
+        (StatusCode::BAD_REQUEST, e.to_string())
+    } else if let Some(e) = rejection.find::<warp::reject::InvalidHeader>() {
+        (StatusCode::BAD_REQUEST, e.to_string())
+    } else if let Some(e) = rejection.find::<warp::reject::MissingHeader>() {
+        (StatusCode::BAD_REQUEST, e.to_string())
+    } else if let Some(e) = rejection.find::<warp::ws::MissingConnectionUpgrade>() {
+        // A plain request to the WebSocket endpoint
+        (StatusCode::BAD_REQUEST, e.to_string())
+    } else if rejection.find::<warp::reject::MethodNotAllowed>().is_some() {
+        (StatusCode::METHOD_NOT_ALLOWED, "method not allowed".to_string())
+    } else {
+        println!("❌ Unhandled rejection: {:?}", rejection);
+        (StatusCode::INTERNAL_SERVER_ERROR, "internal error".to_string())
+    };
+    Ok(json_error(status, message))
+}
@@ -0,0 +1,150 @@
+// Exchange wallet registry: which exchange owns which address
+use ethers::types::{Address, U256};
+use std::collections::{BTreeMap, HashMap};
+
+// Every value `TransferClass::direction` returns
+pub const DIRECTIONS: [&str; 4] = ["deposit", "withdrawal", "internal", "cross_exchange"];
+
+// How a transfer touching at least one exchange wallet is counted
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TransferClass<'a> {
+    Deposit { exchange: &'a str },
+    Withdrawal { exchange: &'a str },
+    // Between two wallets of the same exchange, e.g. hot <-> cold
+    Internal { exchange: &'a str },
+    CrossExchange { from: &'a str, to: &'a str },
+}
+
+impl<'a> TransferClass<'a> {
+    // Stored in transactions.direction
+    pub fn direction(&self) -> &'static str {
+        match self {
+            TransferClass::Deposit { .. } => "deposit",
+            TransferClass::Withdrawal { .. } => "withdrawal",
+            TransferClass::Internal { .. } => "internal",
+            TransferClass::CrossExchange { .. } => "cross_exchange",
+        }
+    }
+
+    // Exchange the direction refers to, plus the receiving exchange of a cross-exchange move
+    pub fn exchanges(&self) -> (&'a str, Option<&'a str>) {
+        match *self {
+            TransferClass::Deposit { exchange }
+            | TransferClass::Withdrawal { exchange }
+            | TransferClass::Internal { exchange } => (exchange, None),
+            TransferClass::CrossExchange { from, to } => (from, Some(to)),
+        }
+    }
+}
+
+pub struct ExchangeRegistry {
+    by_address: HashMap<Address, String>,
+    count_internal: bool,
+}
+
+impl ExchangeRegistry {
+    // Build the reverse address -> exchange index from the configured exchange -> addresses map
+    pub fn new(exchanges: &BTreeMap<String, Vec<Address>>, count_internal: bool) -> anyhow::Result<Self> {
+        let mut by_address = HashMap::new();
+        for (exchange, addresses) in exchanges {
+            for address in addresses {
+                if let Some(owner) = by_address.insert(*address, exchange.clone()) {
+                    if owner != *exchange {
+                        anyhow::bail!("address {:?} is listed under both {} and {}", address, owner, exchange);
+                    }
+                }
+            }
+        }
+        Ok(ExchangeRegistry { by_address, count_internal })
+    }
+
+    pub fn exchange_of(&self, address: Address) -> Option<&str> {
+        self.by_address.get(&address).map(String::as_str)
+    }
+
+    pub fn classify(&self, from: Address, to: Address) -> Option<TransferClass<'_>> {
+        match (self.exchange_of(from), self.exchange_of(to)) {
+            (None, None) => None,
+            (None, Some(exchange)) => Some(TransferClass::Deposit { exchange }),
+            (Some(exchange), None) => Some(TransferClass::Withdrawal { exchange }),
+            (Some(from), Some(to)) if from == to => Some(TransferClass::Internal { exchange: from }),
+            (Some(from), Some(to)) => Some(TransferClass::CrossExchange { from, to }),
+        }
+    }
+
+    // (exchange, inflow, outflow) entries a transfer adds to netflow. Internal moves are
+    // left out unless `count_internal_transfers` is set, in which case they net to zero;
+    // cross-exchange moves are an outflow for the sender and an inflow for the receiver.
+    pub fn flows<'a>(&self, class: TransferClass<'a>, amount: U256) -> Vec<(&'a str, U256, U256)> {
+        let zero = U256::zero();
+        match class {
+            TransferClass::Deposit { exchange } => vec![(exchange, amount, zero)],
+            TransferClass::Withdrawal { exchange } => vec![(exchange, zero, amount)],
+            TransferClass::Internal { exchange } if self.count_internal => vec![(exchange, amount, amount)],
+            TransferClass::Internal { .. } => vec![],
+            TransferClass::CrossExchange { from, to } => vec![(from, zero, amount), (to, amount, zero)],
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.by_address.len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const BINANCE_HOT: Address = Address::repeat_byte(0x01);
+    const BINANCE_COLD: Address = Address::repeat_byte(0x02);
+    const OKX: Address = Address::repeat_byte(0x03);
+    const USER: Address = Address::repeat_byte(0x04);
+
+    fn registry(count_internal: bool) -> ExchangeRegistry {
+        let exchanges = BTreeMap::from([
+            ("binance".to_string(), vec![BINANCE_HOT, BINANCE_COLD]),
+            ("okx".to_string(), vec![OKX]),
+        ]);
+        ExchangeRegistry::new(&exchanges, count_internal).unwrap()
+    }
+
+    #[test]
+    fn classifies_by_which_side_is_an_exchange() {
+        let registry = registry(false);
+        assert_eq!(registry.classify(USER, BINANCE_HOT), Some(TransferClass::Deposit { exchange: "binance" }));
+        assert_eq!(registry.classify(OKX, USER), Some(TransferClass::Withdrawal { exchange: "okx" }));
+        assert_eq!(registry.classify(USER, USER), None);
+    }
+
+    #[test]
+    fn tells_internal_from_cross_exchange() {
+        let registry = registry(false);
+        assert_eq!(registry.classify(BINANCE_HOT, BINANCE_COLD), Some(TransferClass::Internal { exchange: "binance" }));
+        assert_eq!(registry.classify(BINANCE_COLD, OKX), Some(TransferClass::CrossExchange { from: "binance", to: "okx" }));
+    }
+
+    #[test]
+    fn internal_moves_count_only_when_enabled() {
+        let amount = U256::from(5);
+        let internal = TransferClass::Internal { exchange: "binance" };
+        assert!(registry(false).flows(internal, amount).is_empty());
+        assert_eq!(registry(true).flows(internal, amount), vec![("binance", amount, amount)]);
+    }
+
+    #[test]
+    fn cross_exchange_moves_both_sides() {
+        let amount = U256::from(5);
+        let class = TransferClass::CrossExchange { from: "binance", to: "okx" };
+        assert_eq!(registry(false).flows(class, amount), vec![("binance", U256::zero(), amount), ("okx", amount, U256::zero())]);
+        assert_eq!(class.exchanges(), ("binance", Some("okx")));
+    }
+
+    #[test]
+    fn rejects_address_under_two_exchanges() {
+        let exchanges = BTreeMap::from([
+            ("binance".to_string(), vec![BINANCE_HOT]),
+            ("okx".to_string(), vec![BINANCE_HOT]),
+        ]);
+        assert!(ExchangeRegistry::new(&exchanges, false).is_err());
+    }
+}
@@ -0,0 +1,367 @@
+// Live push of classified transfers and the netflow they moved, over WebSocket and
+// Server-Sent Events
+use ethers::types::U256;
+use futures_util::{SinkExt, Stream, StreamExt};
+use serde::Deserialize;
+use std::collections::{HashMap, VecDeque};
+use std::convert::Infallible;
+use std::sync::Arc;
+use tokio::sync::broadcast::{self, error::RecvError};
+use warp::sse;
+use warp::ws::{Message, WebSocket};
+
+use crate::amount::{SignedAmount, parse_amount};
+use crate::storage::{Checkpoint, NetflowUpdate, Storage};
+
+// Events a client may fall behind by before it starts missing them
+const CAPACITY: usize = 1_024;
+
+// A transfer applied to storage, with the cumulative netflow of each exchange it moved
+#[derive(Clone)]
+pub struct FeedEvent {
+    pub chain_id: u64,
+    pub position: Checkpoint,
+    pub tx_hash: Option<String>,
+    pub token: String,
+    pub from: String,
+    pub to: String,
+    pub amount: U256,
+    pub direction: String,
+    pub exchange: String,
+    pub counterparty: Option<String>,
+    pub netflows: Vec<(String, SignedAmount)>,
+}
+
+impl FeedEvent {
+    fn transfer_json(&self) -> serde_json::Value {
+        serde_json::json!({
+            "type": "transfer",
+            "chain_id": self.chain_id,
+            "block_number": self.position.block_number,
+            "log_index": self.position.log_index,
+            "tx_hash": self.tx_hash,
+            "token": self.token,
+            "from": self.from,
+            "to": self.to,
+            "amount": self.amount.to_string(),
+            "direction": self.direction,
+            "exchange": self.exchange,
+            "counterparty_exchange": self.counterparty,
+        })
+    }
+
+    // The transfer, then one netflow update per exchange it moved
+    fn messages(&self) -> Vec<serde_json::Value> {
+        let updates = self.netflows.iter().map(|(exchange, cumulative)| serde_json::json!({
+            "type": "netflow",
+            "chain_id": self.chain_id,
+            "block_number": self.position.block_number,
+            "log_index": self.position.log_index,
+            "exchange": exchange,
+            "token": self.token,
+            "cumulative_netflow": cumulative.to_string(),
+        }));
+        std::iter::once(self.transfer_json()).chain(updates).collect()
+    }
+
+    fn sse_event(&self) -> sse::Event {
+        let netflows = self.netflows.iter()
+            .map(|(exchange, cumulative)| netflow_json(exchange, &cumulative.to_string()))
+            .collect();
+        netflow_event(self.chain_id, self.position, &self.tx_hash, &self.token, netflows)
+    }
+}
+
+// Fan-out of applied transfers to every connected client
+#[derive(Clone)]
+pub struct Feed {
+    sender: broadcast::Sender<FeedEvent>,
+}
+
+impl Feed {
+    pub fn new() -> Self {
+        Feed { sender: broadcast::channel(CAPACITY).0 }
+    }
+
+    pub fn publish(&self, event: FeedEvent) {
+        // Fails only when no client is connected
+        let _ = self.sender.send(event);
+    }
+
+    pub fn subscribe(&self) -> broadcast::Receiver<FeedEvent> {
+        self.sender.subscribe()
+    }
+}
+
+// Subscription filters, from the query string on connect or a JSON text message later;
+// each one given must match
+#[derive(Deserialize)]
+pub struct FeedFilter {
+    exchange: Option<String>,
+    token: Option<String>,
+    // Base units
+    min_amount: Option<String>,
+}
+
+struct Subscription {
+    exchange: Option<String>,
+    token: Option<String>,
+    min_amount: Option<U256>,
+}
+
+impl Subscription {
+    fn new(filter: &FeedFilter) -> anyhow::Result<Self> {
+        Ok(Subscription {
+            exchange: filter.exchange.clone(),
+            token: filter.token.as_ref().map(|t| t.to_lowercase()),
+            min_amount: filter.min_amount.as_deref().map(parse_amount).transpose()?,
+        })
+    }
+
+    fn matches(&self, event: &FeedEvent) -> bool {
+        let exchange_matches = self.exchange.as_ref().is_none_or(|exchange| {
+            event.exchange == *exchange || event.counterparty.as_ref() == Some(exchange)
+        });
+        exchange_matches
+            && self.token.as_ref().is_none_or(|token| event.token == *token)
+            && self.min_amount.is_none_or(|min| event.amount >= min)
+    }
+}
+
+fn error_json(error: anyhow::Error) -> serde_json::Value {
+    serde_json::json!({ "type": "error", "error": format!("{:#}", error) })
+}
+
+// Forward matching events to one client until it disconnects
+pub async fn serve_client(socket: WebSocket, feed: Feed, filter: FeedFilter) {
+    let (mut outgoing, mut incoming) = socket.split();
+    let mut subscription = match Subscription::new(&filter) {
+        Ok(subscription) => subscription,
+        Err(e) => {
+            let _ = outgoing.send(Message::text(error_json(e).to_string())).await;
+            let _ = outgoing.close().await;
+            return;
+        }
+    };
+    let mut events = feed.subscribe();
+
+    loop {
+        let reply = tokio::select! {
+            event = events.recv() => match event {
+                Ok(event) if subscription.matches(&event) => event.messages(),
+                Ok(_) => continue,
+                // Tell the client how many events it missed instead of dropping it
+                Err(RecvError::Lagged(missed)) => vec![serde_json::json!({ "type": "lagged", "missed": missed })],
+                Err(RecvError::Closed) => return,
+            },
+            message = incoming.next() => match message {
+                // A text message replaces the subscription filters
+                Some(Ok(message)) if message.is_text() => {
+                    let filter = serde_json::from_str::<FeedFilter>(message.to_str().unwrap_or_default());
+                    match filter.map_err(anyhow::Error::from).and_then(|f| Subscription::new(&f)) {
+                        Ok(updated) => {
+                            subscription = updated;
+                            continue;
+                        }
+                        Err(e) => vec![error_json(e)],
+                    }
+                }
+                Some(Ok(message)) if message.is_close() => return,
+                // Pings are answered by warp
+                Some(Ok(_)) => continue,
+                Some(Err(_)) | None => return,
+            },
+        };
+
+        for message in reply {
+            if outgoing.send(Message::text(message.to_string())).await.is_err() {
+                return;
+            }
+        }
+    }
+}
+
+// SSE sends one `netflow` event per log with id "chain_id-block_number-log_index", so the
+// Last-Event-ID of a reconnecting client says where to resume
+
+// netflows rows read per replay query
+const REPLAY_PAGE: u64 = 500;
+
+fn netflow_json(exchange: &str, cumulative_netflow: &str) -> serde_json::Value {
+    serde_json::json!({ "exchange": exchange, "cumulative_netflow": cumulative_netflow })
+}
+
+fn netflow_event(
+    chain_id: u64,
+    position: Checkpoint,
+    tx_hash: &Option<String>,
+    token: &str,
+    netflows: Vec<serde_json::Value>,
+) -> sse::Event {
+    let data = serde_json::json!({
+        "chain_id": chain_id,
+        "block_number": position.block_number,
+        "log_index": position.log_index,
+        "tx_hash": tx_hash,
+        "token": token,
+        "netflows": netflows,
+    });
+    sse::Event::default()
+        .id(format!("{}-{}-{}", chain_id, position.block_number, position.log_index))
+        .event("netflow")
+        .data(data.to_string())
+}
+
+// Chain and position of a client's Last-Event-ID
+pub fn parse_event_id(last_event_id: &str) -> anyhow::Result<(u64, Checkpoint)> {
+    let invalid = || anyhow::anyhow!("invalid Last-Event-ID {:?}", last_event_id);
+    let mut parts = last_event_id.splitn(3, '-').map(|part| part.parse::<u64>().map_err(|_| invalid()));
+    let mut next = || parts.next().unwrap_or_else(|| Err(invalid()));
+    let (chain_id, block_number, log_index) = (next()?, next()?, next()?);
+    Ok((chain_id, Checkpoint { block_number, log_index }))
+}
+
+// One event per log, in the order each log's first row was written
+fn replay_events(rows: &[NetflowUpdate]) -> Vec<sse::Event> {
+    let mut logs: Vec<(&NetflowUpdate, Vec<serde_json::Value>)> = Vec::new();
+    let mut index: HashMap<(u64, Checkpoint), usize> = HashMap::new();
+    for row in rows {
+        let i = *index.entry((row.chain_id, row.position)).or_insert_with(|| {
+            logs.push((row, Vec::new()));
+            logs.len() - 1
+        });
+        logs[i].1.push(netflow_json(&row.exchange, &row.cumulative_netflow));
+    }
+    logs.into_iter()
+        .map(|(row, netflows)| netflow_event(row.chain_id, row.position, &row.tx_hash, &row.token, netflows))
+        .collect()
+}
+
+// A full page may end partway through the rows of a log. Leave that log's rows to the next
+// page, so it is sent as one event rather than two with the same id.
+fn drop_partial_log(rows: &mut Vec<NetflowUpdate>) {
+    let Some(last) = rows.last().map(|row| (row.chain_id, row.position)) else {
+        return;
+    };
+    // A page holding a single log is kept whole, or replay would stop advancing
+    if let Some(keep) = rows.iter().rposition(|row| (row.chain_id, row.position) != last) {
+        rows.truncate(keep + 1);
+    }
+}
+
+struct SseState {
+    storage: Arc<dyn Storage>,
+    events: broadcast::Receiver<FeedEvent>,
+    // netflows row to replay after; None once caught up
+    replay_after: Option<u64>,
+    pending: VecDeque<sse::Event>,
+    // Last replayed position per chain; live events up to it were already sent
+    replayed: HashMap<u64, Checkpoint>,
+}
+
+// Replay what a resuming client missed from storage, then follow the live feed.
+// Subscribing first means nothing written during the replay is lost.
+pub fn sse_stream(
+    storage: Arc<dyn Storage>,
+    feed: &Feed,
+    replay_after: Option<u64>,
+) -> impl Stream<Item = Result<sse::Event, Infallible>> {
+    let state = SseState {
+        storage,
+        events: feed.subscribe(),
+        replay_after,
+        pending: VecDeque::new(),
+        replayed: HashMap::new(),
+    };
+    futures_util::stream::unfold(state, |mut state| async move {
+        loop {
+            if let Some(event) = state.pending.pop_front() {
+                return Some((Ok(event), state));
+            }
+
+            if let Some(after) = state.replay_after {
+                let mut rows = match state.storage.netflow_updates(after, REPLAY_PAGE).await {
+                    Ok(rows) => rows,
+                    Err(e) => {
+                        println!("❌ SSE replay failed: {:#}", e);
+                        return None;
+                    }
+                };
+                if rows.len() as u64 == REPLAY_PAGE {
+                    drop_partial_log(&mut rows);
+                }
+                state.replay_after = rows.last().map(|row| row.seq);
+                for row in &rows {
+                    state.replayed.insert(row.chain_id, row.position);
+                }
+                state.pending.extend(replay_events(&rows));
+                continue;
+            }
+
+            match state.events.recv().await {
+                Ok(event) => {
+                    // Internal moves write no netflows rows; replay could never repeat them
+                    let empty = event.netflows.is_empty();
+                    let sent = state.replayed.get(&event.chain_id).is_some_and(|p| *p >= event.position);
+                    if !empty && !sent {
+                        return Some((Ok(event.sse_event()), state));
+                    }
+                }
+                // Ending the stream makes the client reconnect with its Last-Event-ID,
+                // which replays whatever it lagged behind on
+                Err(_) => return None,
+            }
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn update(seq: u64, block_number: u64, log_index: u64, exchange: &str) -> NetflowUpdate {
+        NetflowUpdate {
+            seq,
+            chain_id: 137,
+            position: Checkpoint { block_number, log_index },
+            tx_hash: None,
+            exchange: exchange.to_string(),
+            token: "0xtoken".to_string(),
+            cumulative_netflow: "0".to_string(),
+        }
+    }
+
+    #[test]
+    fn parses_event_id() {
+        let (chain_id, position) = parse_event_id("137-50000000-12").unwrap();
+        assert_eq!((chain_id, position.block_number, position.log_index), (137, 50_000_000, 12));
+    }
+
+    #[test]
+    fn rejects_malformed_event_ids() {
+        for id in ["", "137", "137-1", "137-1-x", "137-1-2-3", "-1-2-3"] {
+            assert!(parse_event_id(id).is_err(), "{:?}", id);
+        }
+    }
+
+    #[test]
+    fn full_page_ends_on_a_log_boundary() {
+        // The cross-exchange log at (11, 0) wrote two rows; only the first made this page
+        let mut rows = vec![update(1, 10, 0, "binance"), update(2, 10, 1, "okx"), update(3, 11, 0, "binance")];
+        drop_partial_log(&mut rows);
+        assert_eq!(rows.iter().map(|row| row.seq).collect::<Vec<_>>(), vec![1, 2]);
+    }
+
+    #[test]
+    fn page_of_one_log_is_kept() {
+        let mut rows = vec![update(1, 10, 0, "binance"), update(2, 10, 0, "okx")];
+        drop_partial_log(&mut rows);
+        assert_eq!(rows.len(), 2);
+    }
+
+    #[test]
+    fn replays_one_event_per_log() {
+        let rows = [update(1, 10, 0, "binance"), update(2, 10, 0, "okx"), update(3, 11, 0, "binance")];
+        assert_eq!(replay_events(&rows).len(), 2);
+    }
+}
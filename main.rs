@@ -1,178 +1,863 @@
-use rusqlite::{Connection, params};
 use serde::Deserialize;
-use std::collections::HashSet;
-use std::fs;
-use warp::Filter;
-use tokio;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::convert::Infallible;
+use std::ops::RangeInclusive;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use warp::Filter as _;
 use ethers::prelude::*;
 use ethers::types::{Address, Filter, Log};
-use std::str::FromStr;
 use tokio_stream::StreamExt;
 
-#[derive(Deserialize)]
-struct Config {
-    polygon: Polygon,
-    token: Token,
-    exchanges: Exchanges,
-}
+mod amount;
+mod config;
+mod error;
+mod exchange;
+mod feed;
+mod reorg;
+mod rollup;
+mod storage;
+mod token;
+mod transfer;
 
-#[derive(Deserialize)]
-struct Polygon { rpc_url: String }
-#[derive(Deserialize)]
-struct Token { pol_address: String }
-#[derive(Deserialize)]
-struct Exchanges { binance: Vec<String> }
+use amount::{Flow, format_scaled, parse_amount};
+use config::{Chain, IngestionMode};
+use error::ApiError;
+use exchange::{DIRECTIONS, ExchangeRegistry, TransferClass};
+use feed::{Feed, FeedEvent, FeedFilter};
+use storage::{BucketQuery, Checkpoint, LogRecord, Storage, TransferCursor, TransferQuery, TransferRecord, WindowQuery};
+use transfer::{Decoded, decode_transfer, transfer_filter};
 
 #[tokio::main]
 async fn main() {
     // Load config.toml
-    let config_text = fs::read_to_string("config.toml").expect("Cannot read config.toml");
-    let config: Config = toml::from_str(&config_text).expect("Invalid config.toml");
-
-    // Open SQLite DB
-    let conn = Connection::open("netflow.db").expect("DB open failed");
-
-    // Create tables
-    conn.execute_batch(
-        "CREATE TABLE IF NOT EXISTS transactions (
-            id INTEGER PRIMARY KEY AUTOINCREMENT,
-            block_number BIGINT,
-            tx_hash TEXT,
-            from_address TEXT,
-            to_address TEXT,
-            amount TEXT,
-            timestamp DATETIME DEFAULT CURRENT_TIMESTAMP
-        );
-        CREATE TABLE IF NOT EXISTS netflows (
-            id INTEGER PRIMARY KEY AUTOINCREMENT,
-            exchange TEXT,
-            inflow TEXT,
-            outflow TEXT,
-            cumulative_netflow TEXT,
-            last_updated DATETIME DEFAULT CURRENT_TIMESTAMP
-        );"
-    ).unwrap();
-
-    // Store Binance addresses in a set
-    let binance_addresses: HashSet<String> = config.exchanges.binance.into_iter().collect();
-    println!("✅ Loaded {} Binance addresses", binance_addresses.len());
-
-    // Start blockchain listener in background
-    let rpc_url = config.polygon.rpc_url.clone();
-    let pol_addr = config.token.pol_address.clone();
-    let binance_set = binance_addresses.clone();
-    let conn_clone = conn.clone();
-
-    tokio::spawn(async move {
-        listen_transfers(&rpc_url, &pol_addr, &binance_set, &conn_clone)
-            .await
-            .expect("Listener crashed");
+    let config = config::load_config("config.toml").unwrap_or_else(|e| {
+        eprintln!("❌ {:#}", e);
+        std::process::exit(1);
     });
 
-    // Simulate some flow for demonstration
-    simulate_flow(&conn, "binance", "1000", "200");
+    // Index each chain's exchange wallets by address
+    let registries: Vec<ExchangeRegistry> = config.chains.iter().map(|chain| {
+        ExchangeRegistry::new(&chain.exchanges, chain.count_internal_transfers).unwrap_or_else(|e| {
+            eprintln!("❌ {}: invalid exchanges config: {:#}", chain.name, e);
+            std::process::exit(1);
+        })
+    }).collect();
+
+    // Open the configured database, creating its tables if needed
+    let storage = storage::open(&config.storage).await.unwrap_or_else(|e| {
+        eprintln!("❌ Cannot open storage: {:#}", e);
+        std::process::exit(1);
+    });
+
+    // Transfers applied by the listeners, pushed to WebSocket clients
+    let feed = Feed::new();
+
+    // Start one blockchain listener per chain in background
+    for (chain, exchanges) in config.chains.iter().zip(registries) {
+        println!("✅ {}: loaded {} addresses of {} exchanges", chain.name, exchanges.len(), chain.exchanges.len());
+
+        let chain = chain.clone();
+        // Each listener writes through its own connection
+        let listener_storage = storage::connect(&config.storage).await.unwrap_or_else(|e| {
+            eprintln!("❌ {}: cannot open storage: {:#}", chain.name, e);
+            std::process::exit(1);
+        });
+        let feed = feed.clone();
+
+        tokio::spawn(async move {
+            listen_transfers(&chain, &exchanges, listener_storage.as_ref(), &feed)
+                .await
+                .expect("Listener crashed");
+        });
+    }
+
+    // Simple HTTP API to fetch finalized and provisional netflow per chain, exchange and token
+    let netflow = warp::path("netflow")
+        .and(warp::path::end())
+        .and(warp::get())
+        .and(warp::query::<NetflowQuery>())
+        .and(with_storage(&storage))
+        .and_then(|query: NetflowQuery, storage: Arc<dyn Storage>| async move {
+            let netflow = netflow_by_exchange(storage.as_ref(), &query).await?;
+            Ok::<_, warp::Rejection>(warp::reply::json(&netflow))
+        });
+
+    // Inflow, outflow, net and transfer count per time bucket for one exchange
+    let series = warp::path!("netflow" / "series")
+        .and(warp::get())
+        .and(warp::query::<SeriesQuery>())
+        .and(with_storage(&storage))
+        .and_then(|query: SeriesQuery, storage: Arc<dyn Storage>| async move {
+            let series = netflow_series(storage.as_ref(), &query).await?;
+            Ok::<_, warp::Rejection>(warp::reply::json(&series))
+        });
+
+    // Netflow of one exchange between two blocks or times
+    let window = warp::path!("netflow" / String)
+        // Leave /netflow/series to its own route, so its errors aren't replaced by this one's
+        .and_then(|exchange: String| async move {
+            if exchange == "series" { Err(warp::reject::not_found()) } else { Ok(exchange) }
+        })
+        .and(warp::get())
+        .and(warp::query::<WindowParams>())
+        .and(with_storage(&storage))
+        .and_then(|exchange: String, query: WindowParams, storage: Arc<dyn Storage>| async move {
+            let window = netflow_window(storage.as_ref(), &exchange, &query).await?;
+            Ok::<_, warp::Rejection>(warp::reply::json(&window))
+        });
+
+    // Individual exchange transfers, oldest first, a page at a time
+    let transfers = warp::path("transfers")
+        .and(warp::path::end())
+        .and(warp::get())
+        .and(warp::query::<TransfersQuery>())
+        .and(with_storage(&storage))
+        .and_then(|query: TransfersQuery, storage: Arc<dyn Storage>| async move {
+            let transfers = list_transfers(storage.as_ref(), &query).await?;
+            Ok::<_, warp::Rejection>(warp::reply::json(&transfers))
+        });
+
+    // Live transfers and netflow updates; filters come from the query string or later text messages
+    let live_feed = feed.clone();
+    let live = warp::path("ws")
+        .and(warp::path::end())
+        .and(warp::ws())
+        .and(warp::query::<FeedFilter>())
+        .map(move |ws: warp::ws::Ws, filter: FeedFilter| {
+            let feed = live_feed.clone();
+            ws.on_upgrade(move |socket| feed::serve_client(socket, feed, filter))
+        });
+
+    // Netflow updates as Server-Sent Events; a Last-Event-ID header replays the ones missed
+    let events = warp::path("events")
+        .and(warp::path::end())
+        .and(warp::get())
+        .and(warp::header::optional::<String>("last-event-id"))
+        .and(with_storage(&storage))
+        .and_then(move |last_event_id: Option<String>, storage: Arc<dyn Storage>| {
+            let feed = feed.clone();
+            async move {
+                let replay_after = match last_event_id {
+                    Some(id) => {
+                        let (chain_id, position) = feed::parse_event_id(&id).map_err(ApiError::bad_request)?;
+                        // Nothing stored at or before the position yet: replay from the start
+                        Some(storage.netflow_seq(chain_id, position).await.map_err(ApiError::from)?.unwrap_or(0))
+                    }
+                    None => None,
+                };
+                let stream = feed::sse_stream(storage, &feed, replay_after);
+                Ok::<_, warp::Rejection>(warp::sse::reply(warp::sse::keep_alive().stream(stream)))
+            }
+        });
 
-    // Simple HTTP API to fetch latest netflow
-    let route = warp::path("netflow")
+    // Reorg counter for operators
+    let reorgs = warp::path("reorgs")
+        .and(warp::path::end())
         .and(warp::get())
-        .map(move || {
-            let conn = Connection::open("netflow.db").unwrap();
-            let mut stmt = conn.prepare(
-                "SELECT exchange, inflow, outflow, cumulative_netflow, last_updated 
-                 FROM netflows ORDER BY id DESC LIMIT 1"
-            ).unwrap();
-            let row = stmt.query_row([], |row| {
-                Ok(serde_json::json!({
-                    "exchange": row.get::<_, String>(0)?,
-                    "inflow": row.get::<_, String>(1)?,
-                    "outflow": row.get::<_, String>(2)?,
-                    "cumulative_netflow": row.get::<_, String>(3)?,
-                    "last_updated": row.get::<_, String>(4)?,
-                }))
-            }).unwrap();
-            warp::reply::json(&row)
+        .and(with_storage(&storage))
+        .and_then(|storage: Arc<dyn Storage>| async move {
+            let (count, last) = storage.reorgs().await.map_err(ApiError::from)?;
+            let last = last.map(|reorg| serde_json::json!({
+                "chain_id": reorg.chain_id,
+                "fork_block": reorg.fork_block,
+                "detected_at_block": reorg.detected_at_block,
+                "detected_at": reorg.detected_at,
+            }));
+            Ok::<_, warp::Rejection>(warp::reply::json(&serde_json::json!({ "reorg_count": count, "last_reorg": last })))
         });
 
     println!("🌐 API running at http://127.0.0.1:3030/netflow");
-    warp::serve(route).run(([127, 0, 0, 1], 3030)).await;
+    let routes = netflow.or(series).or(window).or(transfers).or(live).or(events).or(reorgs);
+    warp::serve(routes.recover(error::handle_rejection)).run(([127, 0, 0, 1], 3030)).await;
+}
+
+// Hand each request the shared storage handle
+fn with_storage(storage: &Arc<dyn Storage>) -> impl warp::Filter<Extract = (Arc<dyn Storage>,), Error = Infallible> + Clone {
+    let storage = storage.clone();
+    warp::any().map(move || storage.clone())
 }
 
-// Simulate some netflow for demonstration purposes
-fn simulate_flow(conn: &Connection, exchange: &str, inflow: &str, outflow: &str) {
-    conn.execute(
-        "INSERT INTO transactions (block_number, tx_hash, from_address, to_address, amount) 
-         VALUES (?1, ?2, ?3, ?4, ?5)",
-        params![123456, "0xtesthash", "0xfrom", "0xto", inflow],
-    ).unwrap();
+#[derive(Deserialize)]
+struct NetflowQuery {
+    // Only report this chain
+    chain_id: Option<u64>,
+    // Add up the same exchange and token symbol across chains
+    #[serde(default)]
+    sum_chains: bool,
+    // Also report what accumulated after the end of this block
+    since_block: Option<u64>,
+}
 
-    let cumulative: i128 = inflow.parse::<i128>().unwrap() - outflow.parse::<i128>().unwrap();
-    conn.execute(
-        "INSERT INTO netflows (exchange, inflow, outflow, cumulative_netflow) 
-         VALUES (?1, ?2, ?3, ?4)",
-        params![exchange, inflow, outflow, cumulative.to_string()],
-    ).unwrap();
+#[derive(Default)]
+struct NetflowGroup {
+    finalized: Flow,
+    provisional: Flow,
+    since: Flow,
+    last_updated: String,
+    chain_ids: BTreeSet<u64>,
+    // Decimals shared by every token in the group; None if unknown or mixed
+    decimals: Option<u8>,
 }
 
-// Listen for real POL transfers in real-time
+// Cumulative totals since tracking began: `finalized` only counts confirmed transfers,
+// `provisional` also includes unconfirmed ones
+async fn netflow_by_exchange(storage: &dyn Storage, query: &NetflowQuery) -> Result<serde_json::Value, ApiError> {
+    let balances = storage.balances(query.chain_id, query.since_block).await?;
+
+    let mut metadata: HashMap<(u64, String), Option<token::TokenMetadata>> = HashMap::new();
+    let mut groups: BTreeMap<(Option<u64>, String, String), NetflowGroup> = BTreeMap::new();
+    for balance in balances {
+        let chain_id = balance.chain_id;
+        let since = balance.at_block.map(|at| balance.provisional.since(&at)).unwrap_or_default();
+
+        let meta_key = (chain_id, balance.token.clone());
+        if !metadata.contains_key(&meta_key) {
+            let meta = storage.token_metadata(chain_id, &balance.token).await?;
+            metadata.insert(meta_key.clone(), meta);
+        }
+        let meta = &metadata[&meta_key];
+
+        // Across chains the token is identified by symbol, since its address differs per chain
+        let key = if query.sum_chains {
+            (None, balance.exchange, meta.as_ref().map_or(balance.token, |m| m.symbol.clone()))
+        } else {
+            (Some(chain_id), balance.exchange, balance.token)
+        };
+
+        let is_new = !groups.contains_key(&key);
+        let group = groups.entry(key).or_default();
+        let decimals = meta.as_ref().map(|m| m.decimals);
+        group.decimals = if is_new || group.decimals == decimals { decimals } else { None };
+        group.chain_ids.insert(chain_id);
+
+        group.finalized.add(balance.finalized.inflow, balance.finalized.outflow)?;
+        group.provisional.add(balance.provisional.inflow, balance.provisional.outflow)?;
+        group.since.add(since.inflow, since.outflow)?;
+        group.last_updated = group.last_updated.clone().max(balance.last_updated);
+    }
+
+    let mut result = Vec::new();
+    for ((chain_id, exchange, token_key), group) in groups {
+        let token_metadata = chain_id
+            .and_then(|c| metadata.get(&(c, token_key.clone())))
+            .and_then(|m| m.as_ref().map(|m| m.to_json(&token_key)));
+        result.push(serde_json::json!({
+            "chain_id": chain_id,
+            "chain_ids": group.chain_ids,
+            "exchange": exchange,
+            "token": token_key,
+            "token_metadata": token_metadata,
+            "finalized": group.finalized.to_json(group.decimals),
+            "provisional": group.provisional.to_json(group.decimals),
+            "since_block": query.since_block,
+            "since": query.since_block.map(|_| group.since.to_json(group.decimals)),
+            "last_updated": group.last_updated,
+        }));
+    }
+    if result.is_empty() {
+        return Err(ApiError::NotFound("no netflow recorded".to_string()));
+    }
+    Ok(serde_json::Value::Array(result))
+}
+
+#[derive(Deserialize)]
+struct SeriesQuery {
+    exchange: String,
+    // Bucket size: 1m, 1h or 1d
+    interval: String,
+    chain_id: Option<u64>,
+    token: Option<String>,
+    // Unix seconds or an ISO 8601 time; `from` is inclusive, `to` exclusive
+    from: Option<String>,
+    to: Option<String>,
+}
+
+// Unix seconds, an RFC 3339 time, or a UTC date or "YYYY-MM-DD HH:MM:SS" time
+fn parse_time(value: &str) -> anyhow::Result<u64> {
+    let invalid = || anyhow::anyhow!("invalid time {:?}", value);
+    if let Ok(seconds) = value.parse() {
+        return Ok(seconds);
+    }
+    let seconds = if let Ok(time) = chrono::DateTime::parse_from_rfc3339(value) {
+        time.timestamp()
+    } else if let Ok(time) = chrono::NaiveDateTime::parse_from_str(value, "%Y-%m-%d %H:%M:%S") {
+        time.and_utc().timestamp()
+    } else {
+        let date = chrono::NaiveDate::parse_from_str(value, "%Y-%m-%d").map_err(|_| invalid())?;
+        date.and_time(chrono::NaiveTime::MIN).and_utc().timestamp()
+    };
+    u64::try_from(seconds).map_err(|_| invalid())
+}
+
+#[derive(Deserialize)]
+struct WindowParams {
+    chain_id: Option<u64>,
+    token: Option<String>,
+    // Inclusive, as on /transfers
+    from_block: Option<u64>,
+    to_block: Option<u64>,
+    // Unix seconds or an ISO 8601 time; `from` is inclusive, `to` exclusive, as on /netflow/series
+    from: Option<String>,
+    to: Option<String>,
+}
+
+// Inflow, outflow and net of one exchange per chain and token within the window
+async fn netflow_window(storage: &dyn Storage, exchange: &str, params: &WindowParams) -> Result<serde_json::Value, ApiError> {
+    let query = WindowQuery {
+        exchange: exchange.to_string(),
+        chain_id: params.chain_id,
+        token: params.token.as_ref().map(|t| t.to_lowercase()),
+        from_block: params.from_block,
+        to_block: params.to_block,
+        from_time: params.from.as_deref().map(parse_time).transpose().map_err(ApiError::bad_request)?,
+        to_time: params.to.as_deref().map(parse_time).transpose().map_err(ApiError::bad_request)?,
+    };
+
+    let mut result = Vec::new();
+    for row in storage.window(&query).await? {
+        let meta = storage.token_metadata(row.chain_id, &row.token).await?;
+        let mut entry = row.flow.to_json(meta.as_ref().map(|m| m.decimals));
+        entry["chain_id"] = row.chain_id.into();
+        entry["exchange"] = exchange.into();
+        entry["token_metadata"] = meta.map(|m| m.to_json(&row.token)).into();
+        entry["token"] = row.token.into();
+        entry["from_block"] = params.from_block.into();
+        entry["to_block"] = params.to_block.into();
+        entry["from"] = params.from.clone().into();
+        entry["to"] = params.to.clone().into();
+        entry["transfer_count"] = row.transfer_count.into();
+        result.push(entry);
+    }
+    if result.is_empty() {
+        return Err(ApiError::NotFound(format!("no netflow recorded for {} in this window", exchange)));
+    }
+    Ok(serde_json::Value::Array(result))
+}
+
+const DEFAULT_PAGE_SIZE: u64 = 100;
+const MAX_PAGE_SIZE: u64 = 1_000;
+
+#[derive(Deserialize)]
+struct TransfersQuery {
+    chain_id: Option<u64>,
+    exchange: Option<String>,
+    // deposit, withdrawal, internal or cross_exchange
+    direction: Option<String>,
+    // Address on either side of the transfer
+    counterparty: Option<String>,
+    // Base units, inclusive
+    min_amount: Option<String>,
+    max_amount: Option<String>,
+    // Inclusive
+    from_block: Option<u64>,
+    to_block: Option<u64>,
+    // `next_cursor` of the previous page
+    cursor: Option<String>,
+    limit: Option<u64>,
+}
+
+// Cursors are "block_number-log_index-chain_id"
+fn format_cursor(cursor: &TransferCursor) -> String {
+    format!("{}-{}-{}", cursor.block_number, cursor.log_index, cursor.chain_id)
+}
+
+fn parse_cursor(value: &str) -> anyhow::Result<TransferCursor> {
+    let invalid = || anyhow::anyhow!("invalid cursor {:?}", value);
+    let mut parts = value.splitn(3, '-').map(|part| part.parse::<u64>().map_err(|_| invalid()));
+    let mut next = || parts.next().unwrap_or_else(|| Err(invalid()));
+    Ok(TransferCursor { block_number: next()?, log_index: next()?, chain_id: next()? })
+}
+
+// An empty page is not an error: the cursor may simply be at the end
+async fn list_transfers(storage: &dyn Storage, params: &TransfersQuery) -> Result<serde_json::Value, ApiError> {
+    let limit = params.limit.unwrap_or(DEFAULT_PAGE_SIZE).clamp(1, MAX_PAGE_SIZE);
+    if let Some(direction) = params.direction.as_deref().filter(|d| !DIRECTIONS.contains(d)) {
+        return Err(ApiError::BadRequest(format!("unknown direction {:?}, expected one of {}", direction, DIRECTIONS.join(", "))));
+    }
+    let counterparty = params.counterparty.as_deref().map(config::parse_address).transpose().map_err(ApiError::BadRequest)?;
+    let min_amount = params.min_amount.as_deref().map(parse_amount).transpose().map_err(ApiError::bad_request)?;
+    let max_amount = params.max_amount.as_deref().map(parse_amount).transpose().map_err(ApiError::bad_request)?;
+    if let (Some(min), Some(max)) = (min_amount, max_amount) {
+        if min > max {
+            return Err(ApiError::BadRequest(format!("min_amount {} is greater than max_amount {}", min, max)));
+        }
+    }
+
+    let query = TransferQuery {
+        chain_id: params.chain_id,
+        exchange: params.exchange.clone(),
+        direction: params.direction.clone(),
+        address: counterparty.map(|a| to_hex(a.as_bytes())),
+        min_amount,
+        max_amount,
+        from_block: params.from_block,
+        to_block: params.to_block,
+        after: params.cursor.as_deref().map(parse_cursor).transpose().map_err(ApiError::bad_request)?,
+        // One extra row tells whether another page follows
+        limit: limit + 1,
+    };
+    let mut rows = storage.transfers(&query).await?;
+    let has_more = rows.len() as u64 > limit;
+    rows.truncate(limit as usize);
+
+    let mut decimals: HashMap<(u64, String), Option<u8>> = HashMap::new();
+    let mut transfers = Vec::new();
+    for row in &rows {
+        let key = (row.position.chain_id, row.token.clone());
+        if !decimals.contains_key(&key) {
+            let meta = storage.token_metadata(key.0, &key.1).await?;
+            decimals.insert(key.clone(), meta.map(|m| m.decimals));
+        }
+        transfers.push(serde_json::json!({
+            "chain_id": row.position.chain_id,
+            "block_number": row.position.block_number,
+            "log_index": row.position.log_index,
+            "block_hash": row.block_hash,
+            "tx_hash": row.tx_hash,
+            "token": row.token,
+            "from": row.from,
+            "to": row.to,
+            "amount": row.amount.to_string(),
+            "amount_decimal": decimals[&key].and_then(|d| format_scaled(row.amount, d)),
+            "direction": row.direction,
+            "exchange": row.exchange,
+            "counterparty_exchange": row.counterparty_exchange,
+            "block_timestamp": row.block_timestamp.and_then(format_time),
+            "ingested_at": row.ingested_at,
+        }));
+    }
+
+    let next_cursor = if has_more { rows.last().map(|row| format_cursor(&row.position)) } else { None };
+    Ok(serde_json::json!({ "transfers": transfers, "next_cursor": next_cursor }))
+}
+
+// "YYYY-MM-DD HH:MM:SS" in UTC, the format stored timestamps are reported in
+fn format_time(seconds: u64) -> Option<String> {
+    chrono::DateTime::from_timestamp(seconds as i64, 0).map(|t| t.format("%Y-%m-%d %H:%M:%S").to_string())
+}
+
+// One series of buckets per chain and token the exchange has netflow in
+async fn netflow_series(storage: &dyn Storage, query: &SeriesQuery) -> Result<serde_json::Value, ApiError> {
+    let seconds = rollup::interval_seconds(&query.interval)
+        .ok_or_else(|| ApiError::BadRequest(format!("unknown interval {:?}", query.interval)))?;
+    // Start from the bucket containing `from`
+    let from = query.from.as_deref().map(parse_time).transpose().map_err(ApiError::bad_request)?.map(|t| t - t % seconds);
+    let to = query.to.as_deref().map(parse_time).transpose().map_err(ApiError::bad_request)?;
+
+    let buckets = storage.buckets(&BucketQuery {
+        exchange: query.exchange.clone(),
+        bucket_size: query.interval.clone(),
+        chain_id: query.chain_id,
+        token: query.token.as_ref().map(|t| t.to_lowercase()),
+        from,
+        to,
+    }).await?;
+
+    let mut series: BTreeMap<(u64, String), Vec<serde_json::Value>> = BTreeMap::new();
+    let mut decimals: HashMap<(u64, String), Option<u8>> = HashMap::new();
+    for bucket in buckets {
+        let key = (bucket.chain_id, bucket.token);
+        if !decimals.contains_key(&key) {
+            let meta = storage.token_metadata(key.0, &key.1).await?;
+            decimals.insert(key.clone(), meta.map(|m| m.decimals));
+        }
+
+        let mut entry = bucket.flow.to_json(decimals[&key]);
+        entry["bucket_start"] = bucket.bucket_start.into();
+        entry["bucket_start_time"] = format_time(bucket.bucket_start).into();
+        entry["transfer_count"] = bucket.transfer_count.into();
+        series.entry(key).or_default().push(entry);
+    }
+
+    let result: Vec<_> = series.into_iter()
+        .map(|((chain_id, token), buckets)| serde_json::json!({
+            "chain_id": chain_id,
+            "exchange": query.exchange,
+            "token": token,
+            "interval": query.interval,
+            "buckets": buckets,
+        }))
+        .collect();
+    if result.is_empty() {
+        return Err(ApiError::NotFound(format!("no netflow recorded for {} in this range", query.exchange)));
+    }
+    Ok(serde_json::Value::Array(result))
+}
+
+// Highest settled block, by the `finalized` tag or `confirmations` below the latest block seen
+async fn finality_boundary<P: JsonRpcClient>(
+    provider: &Provider<P>,
+    chain: &Chain,
+    latest_block: u64,
+) -> anyhow::Result<u64> {
+    if chain.use_finalized_tag {
+        let finalized = provider.get_block(BlockNumber::Finalized).await?.and_then(|b| b.number);
+        return Ok(finalized.map_or(0, |n| n.as_u64()));
+    }
+    Ok(latest_block.saturating_sub(chain.confirmations))
+}
+
+const INITIAL_BACKOFF: Duration = Duration::from_secs(1);
+const MAX_BACKOFF: Duration = Duration::from_secs(60);
+
+// Keep ingestion running, failing over between RPC endpoints with exponential backoff
 async fn listen_transfers(
-    rpc_url: &str,
-    pol_address: &str,
-    binance_addresses: &HashSet<String>,
-    conn: &Connection,
+    chain: &Chain,
+    exchanges: &ExchangeRegistry,
+    storage: &dyn Storage,
+    feed: &Feed,
 ) -> anyhow::Result<()> {
-    let provider = Provider::<Http>::try_from(rpc_url)?;
-    let provider = std::sync::Arc::new(provider);
+    let tokens: Vec<Address> = chain.tokens.iter().map(|t| t.address).collect();
+
+    let endpoints: Vec<&String> = std::iter::once(&chain.rpc_url).chain(&chain.fallback_rpc_urls).collect();
+    let mut last_seen = None;
+    let mut backoff = INITIAL_BACKOFF;
+
+    for rpc_url in endpoints.iter().cycle() {
+        let started = Instant::now();
+        let session = match chain.ingestion {
+            IngestionMode::Subscribe => subscribe_transfers(rpc_url, chain, &tokens, exchanges, storage, feed, &mut last_seen).await,
+            IngestionMode::Poll => poll_transfers(rpc_url, chain, &tokens, exchanges, storage, feed, &mut last_seen).await,
+        };
+        match session {
+            Ok(()) => println!("🔌 {}: connection to {} closed", chain.name, rpc_url),
+            Err(e) => println!("❌ {}: listener on {} failed: {:#}", chain.name, rpc_url, e),
+        }
 
-    // Parse POL token contract address
-    let pol_addr: Address = Address::from_str(pol_address)?;
+        // A connection that stayed up for a while starts the backoff over
+        if started.elapsed() > MAX_BACKOFF {
+            backoff = INITIAL_BACKOFF;
+        }
+        println!("🔁 Reconnecting in {:?}", backoff);
+        tokio::time::sleep(backoff).await;
+        backoff = (backoff * 2).min(MAX_BACKOFF);
+    }
 
-    // ERC20 Transfer event signature
-    let transfer_sig = H256::from_slice(&keccak256("Transfer(address,address,uint256)"));
+    Ok(())
+}
 
-    // Filter logs for this token
-    let filter = Filter::new().address(pol_addr).event(&transfer_sig);
+// Block a new session starts from. Per token that is the later of its checkpointed block and
+// the last block seen by an earlier session (fetched again; logs already applied in it are
+// skipped), else `start_block`; the session starts at the earliest of these.
+async fn resume_block(
+    storage: &dyn Storage,
+    chain_id: u64,
+    tokens: &[Address],
+    last_seen: Option<u64>,
+    start_block: Option<u64>,
+) -> anyhow::Result<Option<u64>> {
+    let mut resume_from: Option<u64> = None;
+    for token in tokens {
+        let checkpoint = storage.checkpoint(chain_id, &to_hex(token.as_bytes())).await?;
+        if let Some(block_number) = checkpoint.map(|c| c.block_number).max(last_seen).or(start_block) {
+            resume_from = Some(resume_from.map_or(block_number, |b| b.min(block_number)));
+        }
+    }
+    Ok(resume_from)
+}
 
+// eth_chainId of a fresh connection, which must match the configured chain
+async fn connected_chain_id<P: JsonRpcClient>(provider: &Provider<P>, chain: &Chain) -> anyhow::Result<u64> {
+    let chain_id = provider.get_chainid().await?.as_u64();
+    if chain_id != chain.chain_id {
+        anyhow::bail!("RPC reports chain id {}, but {} is configured as {}", chain_id, chain.name, chain.chain_id);
+    }
+    Ok(chain_id)
+}
+
+async fn ensure_token_metadata<P: JsonRpcClient + 'static>(
+    provider: &std::sync::Arc<Provider<P>>,
+    storage: &dyn Storage,
+    chain_id: u64,
+    tokens: &[Address],
+) -> anyhow::Result<()> {
+    for token in tokens {
+        token::ensure_metadata(provider.clone(), storage, chain_id, *token).await?;
+    }
+    Ok(())
+}
+
+// One WebSocket session: resume, then listen in real-time until the socket drops
+async fn subscribe_transfers(
+    rpc_url: &str,
+    chain: &Chain,
+    tokens: &[Address],
+    exchanges: &ExchangeRegistry,
+    storage: &dyn Storage,
+    feed: &Feed,
+    last_seen: &mut Option<u64>,
+) -> anyhow::Result<()> {
+    // No internal reconnects: a dropped socket must end the session so the gap gets backfilled
+    let provider = Provider::<Ws>::connect_with_reconnects(rpc_url, 0).await?;
+    let provider = std::sync::Arc::new(provider);
+    let filter = transfer_filter(tokens);
+
+    let chain_id = connected_chain_id(&provider, chain).await?;
+    ensure_token_metadata(&provider, storage, chain_id, tokens).await?;
+    let resume_from = resume_block(storage, chain_id, tokens, *last_seen, chain.start_block).await?;
+
+    // Subscribe before reading the head so blocks mined during the backfill are buffered
     let mut stream = provider.subscribe_logs(&filter).await?;
+    let head = provider.get_block_number().await?.as_u64();
+
+    // Highest block already covered by eth_getLogs, if any
+    let mut synced_to = None;
+    if let Some(resume_from) = resume_from {
+        println!("⏪ {}: backfilling token transfers from block {} to {}", chain.name, resume_from, head);
+        backfill_transfers(&provider, &filter, chain, resume_from..=head, exchanges, storage, feed).await?;
+        synced_to = Some(head);
+    }
+    let boundary = finality_boundary(&provider, chain, head).await?;
+    storage.finalize_up_to(chain_id, boundary).await?;
+    let mut latest_block = head;
+    *last_seen = Some(head);
 
-    println!("🔍 Listening for POL transfers...");
+    println!("🔍 {}: listening for token transfers...", chain.name);
 
     while let Some(log) = stream.next().await {
-        handle_transfer_log(&conn, &log, binance_addresses)?;
+        let covered = log.block_number.is_some_and(|b| synced_to.is_some_and(|s| b.as_u64() <= s));
+        if covered && log.removed != Some(true) {
+            continue;
+        }
+        if let Some(fork_block) = process_log(&provider, chain_id, latest_block, &log, exchanges, storage, feed).await? {
+            // Re-apply the canonical chain from just after the fork
+            let head = provider.get_block_number().await?.as_u64();
+            backfill_transfers(&provider, &filter, chain, fork_block + 1..=head, exchanges, storage, feed).await?;
+            synced_to = Some(head);
+        }
+
+        // Once per new block: the previous one has been received in full, so every token is
+        // checkpointed past it, and provisional netflows are promoted
+        if let Some(block_number) = log.block_number.map(|b| b.as_u64()).filter(|b| *b > latest_block) {
+            latest_block = block_number;
+            *last_seen = Some(block_number);
+            let scanned = Checkpoint { block_number: block_number - 1, log_index: reorg::END_OF_BLOCK };
+            storage.advance_checkpoints(chain_id, &checkpoint_tokens(chain), scanned).await?;
+            let boundary = finality_boundary(&provider, chain, latest_block).await?;
+            storage.finalize_up_to(chain_id, boundary).await?;
+        }
+    }
+
+    Ok(())
+}
+
+// One HTTP polling session: resume, then fetch new blocks with eth_getLogs every `poll_interval_ms`
+async fn poll_transfers(
+    rpc_url: &str,
+    chain: &Chain,
+    tokens: &[Address],
+    exchanges: &ExchangeRegistry,
+    storage: &dyn Storage,
+    feed: &Feed,
+    last_seen: &mut Option<u64>,
+) -> anyhow::Result<()> {
+    let provider = std::sync::Arc::new(Provider::<Http>::try_from(rpc_url)?);
+    let filter = transfer_filter(tokens);
+    let interval = Duration::from_millis(chain.poll_interval_ms);
+
+    let chain_id = connected_chain_id(&provider, chain).await?;
+    ensure_token_metadata(&provider, storage, chain_id, tokens).await?;
+    let resume_from = resume_block(storage, chain_id, tokens, *last_seen, chain.start_block).await?;
+
+    // Without a resume point only blocks mined from now on are followed
+    let mut next_block = match resume_from {
+        Some(block_number) => block_number,
+        None => provider.get_block_number().await?.as_u64() + 1,
+    };
+
+    println!("🔍 {}: polling for token transfers from block {}...", chain.name, next_block);
+
+    loop {
+        let head = provider.get_block_number().await?.as_u64();
+        if head >= next_block {
+            backfill_transfers(&provider, &filter, chain, next_block..=head, exchanges, storage, feed).await?;
+
+            let boundary = finality_boundary(&provider, chain, head).await?;
+            storage.finalize_up_to(chain_id, boundary).await?;
+
+            *last_seen = Some(head);
+            next_block = head + 1;
+        }
+        tokio::time::sleep(interval).await;
+    }
+}
+
+// Page through eth_getLogs for the block range in `backfill_batch_size` block chunks
+async fn backfill_transfers<P: JsonRpcClient>(
+    provider: &Provider<P>,
+    filter: &Filter,
+    chain: &Chain,
+    blocks: RangeInclusive<u64>,
+    exchanges: &ExchangeRegistry,
+    storage: &dyn Storage,
+    feed: &Feed,
+) -> anyhow::Result<()> {
+    let batch_size = chain.backfill_batch_size.max(1);
+    let tokens = checkpoint_tokens(chain);
+    let mut page_start = *blocks.start();
+    'pages: while page_start <= *blocks.end() {
+        let page_end = (page_start + batch_size - 1).min(*blocks.end());
+        let page = filter.clone().from_block(page_start).to_block(page_end);
+        let logs = provider.get_logs(&page).await?;
+
+        for log in &logs {
+            if let Some(fork_block) = process_log(provider, chain.chain_id, *blocks.end(), log, exchanges, storage, feed).await? {
+                // Rolled back; fetch the canonical logs again from just after the fork
+                page_start = fork_block + 1;
+                continue 'pages;
+            }
+        }
+        println!("⏪ Blocks {}-{}: {} transfer logs", page_start, page_end, logs.len());
+        let scanned = Checkpoint { block_number: page_end, log_index: reorg::END_OF_BLOCK };
+        storage.advance_checkpoints(chain.chain_id, &tokens, scanned).await?;
+
+        page_start = page_end + 1;
     }
 
     Ok(())
 }
 
-// Decode and process each transfer
-fn handle_transfer_log(
-    conn: &Connection,
+// Apply a log after checking its block against the stored chain.
+// Returns the fork block if a reorg was rolled back and the caller must re-sync from there.
+async fn process_log<P: JsonRpcClient>(
+    provider: &Provider<P>,
+    chain_id: u64,
+    head: u64,
     log: &Log,
-    binance_addresses: &HashSet<String>,
+    exchanges: &ExchangeRegistry,
+    storage: &dyn Storage,
+    feed: &Feed,
+) -> anyhow::Result<Option<u64>> {
+    // Only exchange transfers need their block's header, for the timestamp
+    let is_exchange_transfer = match decode_transfer(log) {
+        Decoded::Transfer(transfer) => exchanges.classify(transfer.from, transfer.to).is_some(),
+        Decoded::Ignored | Decoded::Malformed => false,
+    };
+    if let Some(fork_block) = reorg::track_block(provider, storage, chain_id, log, is_exchange_transfer, head).await? {
+        let detected_at = log.block_number.unwrap_or_default().as_u64();
+        storage.rollback_to(chain_id, fork_block, detected_at).await?;
+        println!("⚠️ Reorg detected at block {}, rolled back to block {}", detected_at, fork_block);
+        return Ok(Some(fork_block));
+    }
+
+    if log.removed != Some(true) {
+        handle_transfer_log(storage, feed, chain_id, log, exchanges).await?;
+    }
+
+    Ok(None)
+}
+
+// Decode and classify each transfer, then apply it and advance the checkpoint in one storage
+// transaction. Newly applied exchange transfers are published to the live feed.
+async fn handle_transfer_log(
+    storage: &dyn Storage,
+    feed: &Feed,
+    chain_id: u64,
+    log: &Log,
+    exchanges: &ExchangeRegistry,
 ) -> anyhow::Result<()> {
-    let from = format!("0x{}", hex::encode(&log.topics[1].as_bytes()[12..]));
-    let to = format!("0x{}", hex::encode(&log.topics[2].as_bytes()[12..]));
-    let amount: U256 = U256::from_big_endian(&log.data.0);
+    let token = to_hex(log.address.as_bytes());
+    let position = Checkpoint {
+        block_number: log.block_number.ok_or_else(|| anyhow::anyhow!("log without block number"))?.as_u64(),
+        log_index: log.log_index.ok_or_else(|| anyhow::anyhow!("log without log index"))?.as_u64(),
+    };
+    let mut record = LogRecord {
+        chain_id,
+        token: token.clone(),
+        position,
+        block_hash: log.block_hash.map(|h| to_hex(h.as_bytes())),
+        tx_hash: log.transaction_hash.map(|h| to_hex(h.as_bytes())),
+        transfer: None,
+        flows: Vec::new(),
+    };
+
+    let decoded = decode_transfer(log);
+    let transfer = match &decoded {
+        Decoded::Transfer(transfer) => Some(transfer),
+        Decoded::Ignored | Decoded::Malformed => None,
+    };
+    let class = transfer.and_then(|t| exchanges.classify(t.from, t.to));
+
+    if let (Some(transfer), Some(class)) = (transfer, class) {
+        let (exchange, counterparty) = class.exchanges();
+        record.transfer = Some(TransferRecord {
+            from: to_hex(transfer.from.as_bytes()),
+            to: to_hex(transfer.to.as_bytes()),
+            amount: transfer.amount,
+            direction: class.direction(),
+            exchange: exchange.to_string(),
+            counterparty: counterparty.map(str::to_string),
+        });
+        record.flows = exchanges.flows(class, transfer.amount).into_iter()
+            .map(|(exchange, inflow, outflow)| (exchange.to_string(), inflow, outflow))
+            .collect();
+    }
 
-    let mut inflow: i128 = 0;
-    let mut outflow: i128 = 0;
+    // None: the checkpoint shows this log was applied before
+    let Some(cumulative) = storage.apply_log(&record).await? else {
+        return Ok(());
+    };
 
-    if binance_addresses.contains(&to) {
-        inflow = amount.as_u128() as i128;
-        println!("📥 Deposit {} POL to Binance", inflow);
-    } else if binance_addresses.contains(&from) {
-        outflow = amount.as_u128() as i128;
-        println!("📤 Withdrawal {} POL from Binance", outflow);
+    if matches!(decoded, Decoded::Malformed) {
+        println!("⚠️ Skipping malformed transfer log at block {} index {}", position.block_number, position.log_index);
+    }
+    if let (Some(transfer), Some(class)) = (transfer, class) {
+        let amount = transfer.amount;
+        match class {
+            TransferClass::Deposit { exchange } => println!("📥 Deposit {} of {} to {}", amount, token, exchange),
+            TransferClass::Withdrawal { exchange } => println!("📤 Withdrawal {} of {} from {}", amount, token, exchange),
+            TransferClass::Internal { exchange } => println!("🔄 Internal move {} of {} within {}", amount, token, exchange),
+            TransferClass::CrossExchange { from, to } => println!("🔀 Cross-exchange {} of {} from {} to {}", amount, token, from, to),
+        }
+    }
+    for ((exchange, _, _), cumulative) in record.flows.iter().zip(&cumulative) {
+        println!("📊 {} cumulative netflow of {}: {}", exchange, token, cumulative);
     }
 
-    if inflow != 0 || outflow != 0 {
-        let cumulative = inflow - outflow;
-        conn.execute(
-            "INSERT INTO netflows (exchange, inflow, outflow, cumulative_netflow) 
-             VALUES (?1, ?2, ?3, ?4)",
-            params!["binance", inflow.to_string(), outflow.to_string(), cumulative.to_string()],
-        )?;
+    if let Some(transfer) = record.transfer {
+        feed.publish(FeedEvent {
+            chain_id,
+            position,
+            tx_hash: record.tx_hash,
+            token: token.clone(),
+            from: transfer.from,
+            to: transfer.to,
+            amount: transfer.amount,
+            direction: transfer.direction.to_string(),
+            exchange: transfer.exchange,
+            counterparty: transfer.counterparty,
+            netflows: record.flows.into_iter().map(|(exchange, _, _)| exchange).zip(cumulative).collect(),
+        });
     }
 
     Ok(())
 }
 
+// 0x-prefixed lowercase hex, the format addresses and hashes are stored in
+fn to_hex(bytes: &[u8]) -> String {
+    format!("0x{}", hex::encode(bytes))
+}
+
+// The chain's token addresses as checkpoints are keyed
+fn checkpoint_tokens(chain: &Chain) -> Vec<String> {
+    chain.tokens.iter().map(|token| to_hex(token.address.as_bytes())).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn cursor_round_trips() {
+        let cursor = parse_cursor(&format_cursor(&TransferCursor { block_number: 50_000_000, log_index: 12, chain_id: 137 })).unwrap();
+        assert_eq!((cursor.block_number, cursor.log_index, cursor.chain_id), (50_000_000, 12, 137));
+    }
+
+    #[test]
+    fn rejects_malformed_cursors() {
+        for cursor in ["", "1-2", "1-2-", "a-2-3", "1-2-3-4", "-1-2-3", "1-2-99999999999999999999"] {
+            assert!(parse_cursor(cursor).is_err(), "{:?}", cursor);
+        }
+    }
+}
@@ -0,0 +1,98 @@
+// Chain reorganization handling: block hash tracking and fork detection.
+// Rollback itself is `Storage::rollback_to`.
+use ethers::prelude::*;
+
+use crate::storage::{BlockHeader, Storage};
+use crate::to_hex;
+
+// How many recent blocks keep their hashes for fork detection
+pub const BLOCK_HISTORY: u64 = 1_024;
+
+// Checkpoint log index meaning "every log of the block has been applied"
+pub const END_OF_BLOCK: u64 = i64::MAX as u64;
+
+// Check the block a log belongs to against the stored chain, recording it if new.
+// Returns the fork block (last block still canonical) when a reorg is detected.
+// Headers are only fetched when `needs_header` is set, for exchange transfers whose netflow
+// needs the block timestamp; blocks deeper than BLOCK_HISTORY below `head` are settled and
+// not checked for forks, so a backfill from far back costs no extra calls per block.
+pub async fn track_block<P: JsonRpcClient>(
+    provider: &Provider<P>,
+    storage: &dyn Storage,
+    chain_id: u64,
+    log: &Log,
+    needs_header: bool,
+    head: u64,
+) -> anyhow::Result<Option<u64>> {
+    let block_number = log.block_number.ok_or_else(|| anyhow::anyhow!("log without block number"))?.as_u64();
+    let block_hash = log.block_hash.ok_or_else(|| anyhow::anyhow!("log without block hash"))?;
+    let block_hex = to_hex(block_hash.as_bytes());
+    let recent = block_number + BLOCK_HISTORY >= head;
+
+    let stored = storage.block_hash(chain_id, block_number).await?;
+
+    // A removed log only needs undoing if we applied the block it came from
+    if log.removed == Some(true) {
+        if stored.as_deref() != Some(block_hex.as_str()) {
+            return Ok(None);
+        }
+        return find_fork_point(provider, storage, chain_id, block_number).await.map(Some);
+    }
+
+    match stored {
+        Some(hash) if hash == block_hex => Ok(None),
+        Some(_) if recent => find_fork_point(provider, storage, chain_id, block_number).await.map(Some),
+        _ if !needs_header => Ok(None),
+        _ => {
+            // First exchange transfer seen in this block: fetch its header
+            let block = provider.get_block(block_hash).await?
+                .ok_or_else(|| anyhow::anyhow!("block {} not found", block_hex))?;
+            let parent_hex = to_hex(block.parent_hash.as_bytes());
+
+            // The latest block stored below this one must still be canonical. Only blocks with
+            // exchange transfers are stored, so unless it is the parent, look up its canonical hash.
+            let previous = if recent { storage.latest_block_before(chain_id, block_number).await? } else { None };
+            if let Some((prev_number, prev_hash)) = previous {
+                let canonical = if prev_number + 1 == block_number {
+                    Some(parent_hex.clone())
+                } else {
+                    provider.get_block(prev_number).await?.and_then(|b| b.hash).map(|h| to_hex(h.as_bytes()))
+                };
+                if canonical.is_some_and(|hash| hash != prev_hash) {
+                    return find_fork_point(provider, storage, chain_id, prev_number).await.map(Some);
+                }
+            }
+
+            // Stored headers double as the cache `Storage::apply_log` reads block timestamps from
+            let header = BlockHeader {
+                number: block_number,
+                hash: block_hex,
+                parent_hash: parent_hex,
+                timestamp: block.timestamp.as_u64(),
+            };
+            storage.record_block(chain_id, &header).await?;
+            Ok(None)
+        }
+    }
+}
+
+// Walk back through stored blocks below `below` until one is still canonical
+async fn find_fork_point<P: JsonRpcClient>(
+    provider: &Provider<P>,
+    storage: &dyn Storage,
+    chain_id: u64,
+    mut below: u64,
+) -> anyhow::Result<u64> {
+    loop {
+        let stored = storage.latest_block_before(chain_id, below).await?;
+        let Some((block_number, hash)) = stored else {
+            return Ok(below.saturating_sub(1));
+        };
+
+        let canonical = provider.get_block(block_number).await?.and_then(|b| b.hash);
+        if canonical.is_some_and(|h| to_hex(h.as_bytes()) == hash) {
+            return Ok(block_number);
+        }
+        below = block_number;
+    }
+}
@@ -0,0 +1,8 @@
+// Netflow rolled up into minute, hour and day buckets, maintained by the storage backend as netflows rows land
+
+// Bucket sizes by the name used in the `interval` query parameter
+pub const INTERVALS: [(&str, u64); 3] = [("1m", 60), ("1h", 3_600), ("1d", 86_400)];
+
+pub fn interval_seconds(interval: &str) -> Option<u64> {
+    INTERVALS.iter().find(|(name, _)| *name == interval).map(|(_, seconds)| *seconds)
+}
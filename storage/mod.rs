@@ -0,0 +1,355 @@
+// Persistence behind one interface, implemented for SQLite and PostgreSQL
+use async_trait::async_trait;
+use ethers::types::U256;
+use std::sync::Arc;
+
+use crate::amount::{Flow, SignedAmount, parse_amount};
+use crate::config::StorageConfig;
+use crate::rollup::INTERVALS;
+use crate::token::TokenMetadata;
+
+mod postgres;
+mod sqlite;
+
+// Position of the last log processed for a token on a chain
+#[derive(Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct Checkpoint {
+    pub block_number: u64,
+    pub log_index: u64,
+}
+
+// The parts of a block header kept for reorg detection and on-chain timestamps
+#[derive(Clone)]
+pub struct BlockHeader {
+    pub number: u64,
+    pub hash: String,
+    pub parent_hash: String,
+    // Unix seconds
+    pub timestamp: u64,
+}
+
+// A transfer touching an exchange wallet, as stored in `transactions`
+#[derive(Clone)]
+pub struct TransferRecord {
+    pub from: String,
+    pub to: String,
+    pub amount: U256,
+    pub direction: &'static str,
+    pub exchange: String,
+    // Receiving exchange of a cross-exchange transfer, where `exchange` is the sender
+    pub counterparty: Option<String>,
+}
+
+// One processed log. The token's checkpoint always advances to it; the transfer and its
+// (exchange, inflow, outflow) netflow entries are only recorded the first time it is seen.
+#[derive(Clone)]
+pub struct LogRecord {
+    pub chain_id: u64,
+    pub token: String,
+    pub position: Checkpoint,
+    pub block_hash: Option<String>,
+    pub tx_hash: Option<String>,
+    pub transfer: Option<TransferRecord>,
+    pub flows: Vec<(String, U256, U256)>,
+}
+
+// One netflows entry; position fields are None only for rows written before they were tracked
+pub struct FlowEvent<'a> {
+    pub chain_id: u64,
+    pub block_number: Option<u64>,
+    // Unix seconds
+    pub block_timestamp: Option<u64>,
+    pub block_hash: Option<String>,
+    pub tx_hash: Option<String>,
+    pub log_index: Option<u64>,
+    pub exchange: &'a str,
+    pub token: &'a str,
+    pub inflow: U256,
+    pub outflow: U256,
+}
+
+// Running totals of one exchange and token on one chain
+pub struct BalanceRow {
+    pub chain_id: u64,
+    pub exchange: String,
+    pub token: String,
+    // Everything recorded, including unconfirmed transfers
+    pub provisional: Flow,
+    // As of the last finalized netflows row
+    pub finalized: Flow,
+    // As of the end of the requested block, if one was given
+    pub at_block: Option<Flow>,
+    pub last_updated: String,
+}
+
+#[derive(Clone)]
+pub struct BucketQuery {
+    pub exchange: String,
+    pub bucket_size: String,
+    pub chain_id: Option<u64>,
+    pub token: Option<String>,
+    // Unix seconds; `from` is inclusive, `to` exclusive
+    pub from: Option<u64>,
+    pub to: Option<u64>,
+}
+
+pub struct BucketRow {
+    pub chain_id: u64,
+    pub token: String,
+    // Unix seconds
+    pub bucket_start: u64,
+    pub flow: Flow,
+    pub transfer_count: u64,
+}
+
+// Netflow of one exchange over a window; every bound is optional
+#[derive(Clone)]
+pub struct WindowQuery {
+    pub exchange: String,
+    pub chain_id: Option<u64>,
+    pub token: Option<String>,
+    // Inclusive
+    pub from_block: Option<u64>,
+    pub to_block: Option<u64>,
+    // Unix seconds, compared against the block timestamp; `from` is inclusive, `to` exclusive
+    pub from_time: Option<u64>,
+    pub to_time: Option<u64>,
+}
+
+pub struct WindowRow {
+    pub chain_id: u64,
+    pub token: String,
+    pub flow: Flow,
+    pub transfer_count: u64,
+}
+
+// Position of a transfer in /transfers order; chain_id breaks ties between chains
+#[derive(Clone, Copy)]
+pub struct TransferCursor {
+    pub block_number: u64,
+    pub log_index: u64,
+    pub chain_id: u64,
+}
+
+// Filters over stored transfers; block and amount bounds are inclusive
+#[derive(Clone)]
+pub struct TransferQuery {
+    pub chain_id: Option<u64>,
+    // Matches the sending or the receiving exchange
+    pub exchange: Option<String>,
+    pub direction: Option<String>,
+    // Address on either side of the transfer
+    pub address: Option<String>,
+    pub min_amount: Option<U256>,
+    pub max_amount: Option<U256>,
+    pub from_block: Option<u64>,
+    pub to_block: Option<u64>,
+    // Only transfers after this position
+    pub after: Option<TransferCursor>,
+    pub limit: u64,
+}
+
+pub struct TransferRow {
+    pub position: TransferCursor,
+    pub block_hash: Option<String>,
+    pub tx_hash: Option<String>,
+    pub token: String,
+    pub from: String,
+    pub to: String,
+    pub amount: U256,
+    pub direction: String,
+    pub exchange: String,
+    pub counterparty_exchange: Option<String>,
+    // Unix seconds
+    pub block_timestamp: Option<u64>,
+    pub ingested_at: String,
+}
+
+// One netflows row applied for a log, in the order rows were written
+pub struct NetflowUpdate {
+    // netflows row id; increases with every row written
+    pub seq: u64,
+    pub chain_id: u64,
+    pub position: Checkpoint,
+    pub tx_hash: Option<String>,
+    pub exchange: String,
+    pub token: String,
+    pub cumulative_netflow: String,
+}
+
+pub struct ReorgRow {
+    pub chain_id: u64,
+    pub fork_block: u64,
+    pub detected_at_block: u64,
+    pub detected_at: String,
+}
+
+#[async_trait]
+pub trait Storage: Send + Sync {
+    async fn checkpoint(&self, chain_id: u64, token: &str) -> anyhow::Result<Option<Checkpoint>>;
+
+    // Apply a log atomically. Returns the new cumulative netflow of each entry in `flows`,
+    // or None if the token's checkpoint shows the log was applied before.
+    async fn apply_log(&self, record: &LogRecord) -> anyhow::Result<Option<Vec<SignedAmount>>>;
+    // Move each token's checkpoint up to `position` where it is behind or missing. Scanned
+    // blocks without a log for a token would otherwise never be recorded for it.
+    async fn advance_checkpoints(&self, chain_id: u64, tokens: &[String], position: Checkpoint) -> anyhow::Result<()>;
+
+
+    // Mark netflows on a chain at or below `block_number` as finalized
+    async fn finalize_up_to(&self, chain_id: u64, block_number: u64) -> anyhow::Result<u64>;
+
+    async fn token_metadata(&self, chain_id: u64, address: &str) -> anyhow::Result<Option<TokenMetadata>>;
+    async fn store_token_metadata(&self, chain_id: u64, address: &str, metadata: &TokenMetadata) -> anyhow::Result<()>;
+
+    async fn block_hash(&self, chain_id: u64, block_number: u64) -> anyhow::Result<Option<String>>;
+    // Highest stored block below `block_number`, with its hash
+    async fn latest_block_before(&self, chain_id: u64, block_number: u64) -> anyhow::Result<Option<(u64, String)>>;
+    async fn record_block(&self, chain_id: u64, header: &BlockHeader) -> anyhow::Result<()>;
+    // Undo everything applied above `fork_block` and rewind checkpoints to the end of it
+    async fn rollback_to(&self, chain_id: u64, fork_block: u64, detected_at: u64) -> anyhow::Result<()>;
+
+    async fn balances(&self, chain_id: Option<u64>, at_block: Option<u64>) -> anyhow::Result<Vec<BalanceRow>>;
+    async fn buckets(&self, query: &BucketQuery) -> anyhow::Result<Vec<BucketRow>>;
+    // One row per chain and token with netflow in the window
+    async fn window(&self, query: &WindowQuery) -> anyhow::Result<Vec<WindowRow>>;
+    // Transfers matching the filters in (block_number, log_index, chain_id) order
+    async fn transfers(&self, query: &TransferQuery) -> anyhow::Result<Vec<TransferRow>>;
+    // Sequence number of the last netflows row at or before a position on a chain
+    async fn netflow_seq(&self, chain_id: u64, position: Checkpoint) -> anyhow::Result<Option<u64>>;
+    // Netflows rows of logs written after `after_seq`, oldest first
+    async fn netflow_updates(&self, after_seq: u64, limit: u64) -> anyhow::Result<Vec<NetflowUpdate>>;
+    // Number of reorgs recorded, and the latest one
+    async fn reorgs(&self) -> anyhow::Result<(u64, Option<ReorgRow>)>;
+}
+
+// Add up (chain_id, token, inflow, outflow) netflows rows, sorted by chain and token
+fn sum_window(rows: Vec<(u64, String, String, String)>) -> anyhow::Result<Vec<WindowRow>> {
+    let mut result: Vec<WindowRow> = Vec::new();
+    for (chain_id, token, inflow, outflow) in rows {
+        let row = match result.last_mut() {
+            Some(last) if last.chain_id == chain_id && last.token == token => last,
+            _ => {
+                result.push(WindowRow { chain_id, token, flow: Flow::default(), transfer_count: 0 });
+                result.last_mut().unwrap()
+            }
+        };
+        row.flow.add(parse_amount(&inflow)?, parse_amount(&outflow)?)?;
+        row.transfer_count += 1;
+    }
+    Ok(result)
+}
+
+// (inflow, outflow) totals as stored; zero when nothing was recorded yet
+fn to_flow(totals: Option<(String, String)>) -> anyhow::Result<Flow> {
+    Ok(match totals {
+        Some((inflow, outflow)) => Flow { inflow: parse_amount(&inflow)?, outflow: parse_amount(&outflow)? },
+        None => Flow::default(),
+    })
+}
+
+// Running totals after a netflows entry, and the cumulative netflow stored with it
+fn advance_totals(mut totals: Flow, event: &FlowEvent) -> anyhow::Result<(Flow, SignedAmount)> {
+    totals.add(event.inflow, event.outflow)?;
+    let cumulative = totals.netflow();
+    Ok((totals, cumulative))
+}
+
+// (bucket_size, bucket_start) of each rollup bucket an entry falls in. Rows without a block
+// (written before blocks were tracked) have no place on the timeline.
+fn rollup_buckets(event: &FlowEvent) -> Vec<(&'static str, i64)> {
+    let Some(timestamp) = event.block_timestamp else {
+        return Vec::new();
+    };
+    INTERVALS.iter().map(|&(bucket_size, seconds)| (bucket_size, (timestamp - timestamp % seconds) as i64)).collect()
+}
+
+// A bucket's totals and transfer count after adding an entry to its stored (inflow, outflow,
+// transfer_count), or with `revert` taking it back out; None once no entry is left in it
+fn update_bucket(stored: Option<(String, String, i64)>, event: &FlowEvent, revert: bool) -> anyhow::Result<Option<(Flow, i64)>> {
+    let (mut flow, count) = match stored {
+        Some((inflow, outflow, count)) => (to_flow(Some((inflow, outflow)))?, count),
+        None => (Flow::default(), 0),
+    };
+    let count = if revert {
+        flow.remove(event.inflow, event.outflow)?;
+        count - 1
+    } else {
+        flow.add(event.inflow, event.outflow)?;
+        count + 1
+    };
+    Ok((count > 0).then_some((flow, count)))
+}
+
+// Refuse a database migrated by a newer binary, whose schema this one may misread
+fn check_schema_version(version: u64, known: usize) -> anyhow::Result<()> {
+    if version > known as u64 {
+        anyhow::bail!("database schema is at version {}, but this build only knows up to {}; upgrade the indexer", version, known);
+    }
+    Ok(())
+}
+
+// Open the configured backend, migrating its schema to the latest version
+pub async fn open(config: &StorageConfig) -> anyhow::Result<Arc<dyn Storage>> {
+    Ok(match config {
+        StorageConfig::Sqlite { path } => Arc::new(sqlite::SqliteStorage::open(path)?),
+        StorageConfig::Postgres { url } => Arc::new(postgres::PostgresStorage::open(url).await?),
+    })
+}
+
+// Another connection to the configured backend, once `open` has migrated it
+pub async fn connect(config: &StorageConfig) -> anyhow::Result<Arc<dyn Storage>> {
+    Ok(match config {
+        StorageConfig::Sqlite { path } => Arc::new(sqlite::SqliteStorage::connect(path)?),
+        StorageConfig::Postgres { url } => Arc::new(postgres::PostgresStorage::connect(url).await?),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(inflow: u64, outflow: u64, block_timestamp: Option<u64>) -> FlowEvent<'static> {
+        FlowEvent {
+            chain_id: 137,
+            block_number: Some(1),
+            block_timestamp,
+            block_hash: None,
+            tx_hash: None,
+            log_index: Some(0),
+            exchange: "binance",
+            token: "0xtoken",
+            inflow: U256::from(inflow),
+            outflow: U256::from(outflow),
+        }
+    }
+
+    #[test]
+    fn buckets_align_to_each_interval() {
+        assert_eq!(rollup_buckets(&entry(1, 0, Some(90_061))), vec![("1m", 90_060), ("1h", 90_000), ("1d", 86_400)]);
+        assert!(rollup_buckets(&entry(1, 0, None)).is_empty());
+    }
+
+    #[test]
+    fn reverting_the_last_entry_empties_the_bucket() {
+        let event = entry(5, 2, Some(0));
+        let (flow, count) = update_bucket(None, &event, false).unwrap().unwrap();
+        assert_eq!((flow.inflow, flow.outflow, count), (U256::from(5), U256::from(2), 1));
+
+        let stored = Some((flow.inflow.to_string(), flow.outflow.to_string(), count));
+        assert!(update_bucket(stored, &event, true).unwrap().is_none());
+    }
+
+    #[test]
+    fn reverting_more_than_was_added_fails() {
+        let stored = Some(("1".to_string(), "0".to_string(), 2));
+        assert!(update_bucket(stored, &entry(5, 0, Some(0)), true).is_err());
+    }
+
+    #[test]
+    fn cumulative_netflow_goes_negative() {
+        let (totals, cumulative) = advance_totals(Flow { inflow: U256::from(3), outflow: U256::zero() }, &entry(0, 10, None)).unwrap();
+        assert_eq!((totals.inflow, totals.outflow), (U256::from(3), U256::from(10)));
+        assert_eq!(cumulative.to_string(), "-7");
+    }
+}
@@ -0,0 +1,732 @@
+// PostgreSQL backend, for running several indexers and API instances against a shared database
+use async_trait::async_trait;
+use tokio::sync::Mutex;
+use tokio_postgres::{Client, GenericClient, NoTls, Transaction};
+
+use super::{advance_totals, check_schema_version, rollup_buckets, sum_window, to_flow, update_bucket, BalanceRow, BlockHeader, BucketQuery, BucketRow, Checkpoint, FlowEvent, LogRecord, NetflowUpdate, ReorgRow, Storage, TransferCursor, TransferQuery, TransferRecord, TransferRow, WindowQuery, WindowRow};
+use crate::amount::{Flow, SignedAmount, parse_amount};
+use crate::reorg::{BLOCK_HISTORY, END_OF_BLOCK};
+use crate::token::TokenMetadata;
+
+// Migration N brings the schema from version N - 1 to N. Append new ones; never edit applied ones.
+// Numbering is this backend's own: it started out with the tables SQLite reached in several steps.
+const MIGRATIONS: &[&str] = &[
+    INITIAL_SCHEMA,
+    // 2: keyset pagination for /transfers
+    "CREATE INDEX IF NOT EXISTS transactions_by_position ON transactions (block_number, log_index, chain_id);",
+];
+
+// Version 1: the tables this backend was first released with, before versioning; IF NOT EXISTS
+// lets those databases adopt it. Amounts stay decimal TEXT so both backends encode them alike.
+const INITIAL_SCHEMA: &str = "
+    CREATE TABLE IF NOT EXISTS transactions (
+        id BIGSERIAL PRIMARY KEY,
+        chain_id BIGINT,
+        block_number BIGINT,
+        block_hash TEXT,
+        tx_hash TEXT,
+        log_index BIGINT,
+        token TEXT,
+        from_address TEXT,
+        to_address TEXT,
+        amount TEXT,
+        direction TEXT,
+        exchange TEXT,
+        counterparty_exchange TEXT,
+        block_timestamp TIMESTAMPTZ,
+        ingested_at TIMESTAMPTZ DEFAULT now(),
+        UNIQUE (chain_id, tx_hash, log_index)
+    );
+    CREATE TABLE IF NOT EXISTS netflows (
+        id BIGSERIAL PRIMARY KEY,
+        chain_id BIGINT,
+        block_number BIGINT,
+        block_hash TEXT,
+        tx_hash TEXT,
+        log_index BIGINT,
+        exchange TEXT,
+        inflow TEXT,
+        outflow TEXT,
+        token TEXT,
+        cumulative_inflow TEXT,
+        cumulative_outflow TEXT,
+        cumulative_netflow TEXT,
+        finalized BOOLEAN NOT NULL DEFAULT false,
+        block_timestamp TIMESTAMPTZ,
+        last_updated TIMESTAMPTZ DEFAULT now()
+    );
+    CREATE INDEX IF NOT EXISTS netflows_by_exchange ON netflows (chain_id, exchange, token, id);
+    CREATE TABLE IF NOT EXISTS balances (
+        chain_id BIGINT NOT NULL,
+        exchange TEXT NOT NULL,
+        token TEXT NOT NULL,
+        inflow TEXT NOT NULL,
+        outflow TEXT NOT NULL,
+        netflow TEXT NOT NULL,
+        last_updated TIMESTAMPTZ DEFAULT now(),
+        PRIMARY KEY (chain_id, exchange, token)
+    );
+    CREATE TABLE IF NOT EXISTS netflow_rollups (
+        chain_id BIGINT NOT NULL,
+        exchange TEXT NOT NULL,
+        token TEXT NOT NULL,
+        bucket_size TEXT NOT NULL,
+        bucket_start BIGINT NOT NULL,
+        inflow TEXT NOT NULL,
+        outflow TEXT NOT NULL,
+        transfer_count BIGINT NOT NULL,
+        PRIMARY KEY (chain_id, exchange, token, bucket_size, bucket_start)
+    );
+    CREATE TABLE IF NOT EXISTS checkpoints (
+        chain_id BIGINT NOT NULL,
+        token TEXT NOT NULL,
+        block_number BIGINT NOT NULL,
+        log_index BIGINT NOT NULL,
+        last_updated TIMESTAMPTZ DEFAULT now(),
+        PRIMARY KEY (chain_id, token)
+    );
+    CREATE TABLE IF NOT EXISTS tokens (
+        chain_id BIGINT NOT NULL,
+        address TEXT NOT NULL,
+        name TEXT NOT NULL,
+        symbol TEXT NOT NULL,
+        decimals INTEGER NOT NULL,
+        PRIMARY KEY (chain_id, address)
+    );
+    CREATE TABLE IF NOT EXISTS blocks (
+        chain_id BIGINT NOT NULL,
+        block_number BIGINT NOT NULL,
+        block_hash TEXT NOT NULL,
+        parent_hash TEXT NOT NULL,
+        timestamp BIGINT NOT NULL,
+        PRIMARY KEY (chain_id, block_number)
+    );
+    CREATE TABLE IF NOT EXISTS reorgs (
+        id BIGSERIAL PRIMARY KEY,
+        chain_id BIGINT NOT NULL,
+        fork_block BIGINT NOT NULL,
+        detected_at_block BIGINT NOT NULL,
+        detected_at TIMESTAMPTZ DEFAULT now()
+    );";
+
+pub struct PostgresStorage {
+    // Transactions need exclusive access to the client
+    client: Mutex<Client>,
+}
+
+impl PostgresStorage {
+    pub async fn open(url: &str) -> anyhow::Result<Self> {
+        let storage = Self::connect(url).await?;
+        migrate(&mut *storage.client.lock().await).await?;
+        Ok(storage)
+    }
+
+    // A further connection to a database `open` already migrated
+    pub async fn connect(url: &str) -> anyhow::Result<Self> {
+        let (client, connection) = tokio_postgres::connect(url, NoTls).await?;
+        tokio::spawn(async move {
+            if let Err(e) = connection.await {
+                println!("❌ PostgreSQL connection closed: {}", e);
+            }
+        });
+        Ok(PostgresStorage { client: Mutex::new(client) })
+    }
+}
+
+// Lock key serializing migrations across indexers sharing the database
+const MIGRATION_LOCK: i64 = 0x006e_6574_666c_6f77;
+
+// Apply pending migrations in one transaction, holding an advisory lock so concurrent
+// instances don't race
+async fn migrate(client: &mut Client) -> anyhow::Result<()> {
+    let tx = client.transaction().await?;
+    tx.execute("SELECT pg_advisory_xact_lock($1)", &[&MIGRATION_LOCK]).await?;
+    tx.batch_execute(
+        "CREATE TABLE IF NOT EXISTS schema_version (
+            version BIGINT PRIMARY KEY,
+            applied_at TIMESTAMPTZ DEFAULT now()
+        );",
+    ).await?;
+    let version: i64 = tx.query_one("SELECT COALESCE(MAX(version), 0) FROM schema_version", &[]).await?.get(0);
+    let version = version as u64;
+    check_schema_version(version, MIGRATIONS.len())?;
+    for (index, migration) in MIGRATIONS.iter().enumerate().skip(version as usize) {
+        let next = index as i64 + 1;
+        tx.batch_execute(migration).await?;
+        tx.execute("INSERT INTO schema_version (version) VALUES ($1)", &[&next]).await?;
+        println!("🗄️ Migrated PostgreSQL schema to version {}", next);
+    }
+    tx.commit().await?;
+    Ok(())
+}
+
+async fn load_checkpoint<C: GenericClient + Sync>(client: &C, chain_id: u64, token: &str) -> anyhow::Result<Option<Checkpoint>> {
+    let row = client.query_opt(
+        "SELECT block_number, log_index FROM checkpoints WHERE chain_id = $1 AND token = $2",
+        &[&(chain_id as i64), &token],
+    ).await?;
+    Ok(row.map(|row| Checkpoint {
+        block_number: row.get::<_, i64>(0) as u64,
+        log_index: row.get::<_, i64>(1) as u64,
+    }))
+}
+
+async fn save_checkpoint(tx: &Transaction<'_>, chain_id: u64, token: &str, checkpoint: &Checkpoint) -> anyhow::Result<()> {
+    tx.execute(
+        "INSERT INTO checkpoints (chain_id, token, block_number, log_index)
+         VALUES ($1, $2, $3, $4)
+         ON CONFLICT (chain_id, token) DO UPDATE SET
+            block_number = excluded.block_number,
+            log_index = excluded.log_index,
+            last_updated = now()",
+        &[&(chain_id as i64), &token, &(checkpoint.block_number as i64), &(checkpoint.log_index as i64)],
+    ).await?;
+    Ok(())
+}
+
+async fn block_timestamp(tx: &Transaction<'_>, chain_id: u64, block_number: u64) -> anyhow::Result<Option<u64>> {
+    let row = tx.query_opt(
+        "SELECT timestamp FROM blocks WHERE chain_id = $1 AND block_number = $2",
+        &[&(chain_id as i64), &(block_number as i64)],
+    ).await?;
+    Ok(row.map(|row| row.get::<_, i64>(0) as u64))
+}
+
+// Store a classified transfer; false if it was already recorded
+async fn record_transfer(
+    tx: &Transaction<'_>,
+    record: &LogRecord,
+    transfer: &TransferRecord,
+    block_timestamp: Option<u64>,
+) -> anyhow::Result<bool> {
+    let inserted = tx.execute(
+        "INSERT INTO transactions (chain_id, block_number, block_hash, tx_hash, log_index, token,
+                                   from_address, to_address, amount, direction, exchange, counterparty_exchange,
+                                   block_timestamp)
+         VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11, $12, to_timestamp($13::BIGINT))
+         ON CONFLICT (chain_id, tx_hash, log_index) DO NOTHING",
+        &[
+            &(record.chain_id as i64),
+            &(record.position.block_number as i64),
+            &record.block_hash,
+            &record.tx_hash,
+            &(record.position.log_index as i64),
+            &record.token,
+            &transfer.from,
+            &transfer.to,
+            &transfer.amount.to_string(),
+            &transfer.direction,
+            &transfer.exchange,
+            &transfer.counterparty,
+            &block_timestamp.map(|t| t as i64),
+        ],
+    ).await?;
+    Ok(inserted > 0)
+}
+
+// First two columns of an optional row as (inflow, outflow) totals
+fn row_flow(row: Option<tokio_postgres::Row>) -> anyhow::Result<Flow> {
+    to_flow(row.map(|row| (row.get(0), row.get(1))))
+}
+
+async fn finalized_totals(client: &Client, chain_id: u64, exchange: &str, token: &str) -> anyhow::Result<Flow> {
+    let row = client.query_opt(
+        "SELECT cumulative_inflow, cumulative_outflow FROM netflows
+         WHERE chain_id = $1 AND exchange = $2 AND token = $3 AND finalized
+         ORDER BY id DESC LIMIT 1",
+        &[&(chain_id as i64), &exchange, &token],
+    ).await?;
+    row_flow(row)
+}
+
+async fn totals_at_block(client: &Client, chain_id: u64, exchange: &str, token: &str, block_number: u64) -> anyhow::Result<Flow> {
+    let row = client.query_opt(
+        "SELECT cumulative_inflow, cumulative_outflow FROM netflows
+         WHERE chain_id = $1 AND exchange = $2 AND token = $3 AND block_number <= $4
+         ORDER BY id DESC LIMIT 1",
+        &[&(chain_id as i64), &exchange, &token, &(block_number as i64)],
+    ).await?;
+    row_flow(row)
+}
+
+// Insert a netflows row carrying the new running totals and advance the balance and rollups.
+// Call inside the transaction that applies the event so all of them move together.
+async fn record_flow(tx: &Transaction<'_>, event: &FlowEvent<'_>) -> anyhow::Result<SignedAmount> {
+    // Lock the balance row so concurrent writers for the same key serialize
+    let row = tx.query_opt(
+        "SELECT inflow, outflow FROM balances WHERE chain_id = $1 AND exchange = $2 AND token = $3 FOR UPDATE",
+        &[&(event.chain_id as i64), &event.exchange, &event.token],
+    ).await?;
+    let (totals, cumulative) = advance_totals(row_flow(row)?, event)?;
+
+    tx.execute(
+        "INSERT INTO netflows (chain_id, block_number, block_hash, tx_hash, log_index, exchange, token, inflow, outflow,
+                               cumulative_inflow, cumulative_outflow, cumulative_netflow, block_timestamp)
+         VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11, $12, to_timestamp($13::BIGINT))",
+        &[
+            &(event.chain_id as i64),
+            &event.block_number.map(|b| b as i64),
+            &event.block_hash,
+            &event.tx_hash,
+            &event.log_index.map(|i| i as i64),
+            &event.exchange,
+            &event.token,
+            &event.inflow.to_string(),
+            &event.outflow.to_string(),
+            &totals.inflow.to_string(),
+            &totals.outflow.to_string(),
+            &cumulative.to_string(),
+            &event.block_timestamp.map(|t| t as i64),
+        ],
+    ).await?;
+    tx.execute(
+        "INSERT INTO balances (chain_id, exchange, token, inflow, outflow, netflow) VALUES ($1, $2, $3, $4, $5, $6)
+         ON CONFLICT (chain_id, exchange, token) DO UPDATE SET
+             inflow = excluded.inflow, outflow = excluded.outflow, netflow = excluded.netflow,
+             last_updated = now()",
+        &[
+            &(event.chain_id as i64),
+            &event.exchange,
+            &event.token,
+            &totals.inflow.to_string(),
+            &totals.outflow.to_string(),
+            &cumulative.to_string(),
+        ],
+    ).await?;
+    apply_rollups(tx, event, false).await?;
+    Ok(cumulative)
+}
+
+// Add a netflows row to (or with `revert`, take it out of) the bucket of each size
+// its block timestamp falls in
+async fn apply_rollups(tx: &Transaction<'_>, event: &FlowEvent<'_>, revert: bool) -> anyhow::Result<()> {
+    for (bucket_size, bucket_start) in rollup_buckets(event) {
+        let chain_id = event.chain_id as i64;
+
+        let row = tx.query_opt(
+            "SELECT inflow, outflow, transfer_count FROM netflow_rollups
+             WHERE chain_id = $1 AND exchange = $2 AND token = $3 AND bucket_size = $4 AND bucket_start = $5
+             FOR UPDATE",
+            &[&chain_id, &event.exchange, &event.token, &bucket_size, &bucket_start],
+        ).await?;
+        let stored = row.map(|row| (row.get(0), row.get(1), row.get(2)));
+
+        match update_bucket(stored, event, revert)? {
+            None => {
+                tx.execute(
+                    "DELETE FROM netflow_rollups
+                     WHERE chain_id = $1 AND exchange = $2 AND token = $3 AND bucket_size = $4 AND bucket_start = $5",
+                    &[&chain_id, &event.exchange, &event.token, &bucket_size, &bucket_start],
+                ).await?;
+            }
+            Some((flow, count)) => {
+                tx.execute(
+                    "INSERT INTO netflow_rollups
+                     (chain_id, exchange, token, bucket_size, bucket_start, inflow, outflow, transfer_count)
+                     VALUES ($1, $2, $3, $4, $5, $6, $7, $8)
+                     ON CONFLICT (chain_id, exchange, token, bucket_size, bucket_start) DO UPDATE SET
+                         inflow = excluded.inflow, outflow = excluded.outflow, transfer_count = excluded.transfer_count",
+                    &[
+                        &chain_id,
+                        &event.exchange,
+                        &event.token,
+                        &bucket_size,
+                        &bucket_start,
+                        &flow.inflow.to_string(),
+                        &flow.outflow.to_string(),
+                        &count,
+                    ],
+                ).await?;
+            }
+        }
+    }
+    Ok(())
+}
+
+// Take the netflows rows above `fork_block` back out of their buckets; call before deleting them
+async fn revert_rollups_above(tx: &Transaction<'_>, chain_id: u64, fork_block: u64) -> anyhow::Result<()> {
+    let rows = tx.query(
+        "SELECT exchange, token, inflow, outflow, EXTRACT(EPOCH FROM block_timestamp)::BIGINT FROM netflows
+         WHERE chain_id = $1 AND block_number > $2 AND block_timestamp IS NOT NULL",
+        &[&(chain_id as i64), &(fork_block as i64)],
+    ).await?;
+
+    for row in rows {
+        let event = FlowEvent {
+            chain_id,
+            block_number: None,
+            block_timestamp: Some(row.get::<_, i64>(4) as u64),
+            block_hash: None,
+            tx_hash: None,
+            log_index: None,
+            exchange: row.get(0),
+            token: row.get(1),
+            inflow: parse_amount(row.get(2))?,
+            outflow: parse_amount(row.get(3))?,
+        };
+        apply_rollups(tx, &event, true).await?;
+    }
+    Ok(())
+}
+
+// Reset a chain's balances to its latest remaining netflows rows, after a rollback deleted newer ones
+async fn rebuild_balances(tx: &Transaction<'_>, chain_id: u64) -> anyhow::Result<()> {
+    tx.execute("DELETE FROM balances WHERE chain_id = $1", &[&(chain_id as i64)]).await?;
+    tx.execute(
+        "INSERT INTO balances (chain_id, exchange, token, inflow, outflow, netflow, last_updated)
+         SELECT chain_id, exchange, token, cumulative_inflow, cumulative_outflow, cumulative_netflow, last_updated
+         FROM netflows n
+         WHERE chain_id = $1 AND id = (
+             SELECT MAX(id) FROM netflows WHERE chain_id = n.chain_id AND exchange = n.exchange AND token = n.token
+         )",
+        &[&(chain_id as i64)],
+    ).await?;
+    Ok(())
+}
+
+#[async_trait]
+impl Storage for PostgresStorage {
+    async fn checkpoint(&self, chain_id: u64, token: &str) -> anyhow::Result<Option<Checkpoint>> {
+        load_checkpoint(&*self.client.lock().await, chain_id, token).await
+    }
+
+    async fn apply_log(&self, record: &LogRecord) -> anyhow::Result<Option<Vec<SignedAmount>>> {
+        let mut client = self.client.lock().await;
+        let tx = client.transaction().await?;
+
+        // Skip logs at or before the checkpoint (re-fetched after a restart or resubscription)
+        if let Some(checkpoint) = load_checkpoint(&tx, record.chain_id, &record.token).await? {
+            let position = (record.position.block_number, record.position.log_index);
+            if position <= (checkpoint.block_number, checkpoint.log_index) {
+                return Ok(None);
+            }
+        }
+
+        let block_timestamp = block_timestamp(&tx, record.chain_id, record.position.block_number).await?;
+
+        // The unique (chain_id, tx_hash, log_index) key catches a transfer applied before
+        let is_new = match &record.transfer {
+            Some(transfer) => record_transfer(&tx, record, transfer, block_timestamp).await?,
+            None => true,
+        };
+
+        let mut cumulative = Vec::new();
+        if is_new {
+            for (exchange, inflow, outflow) in &record.flows {
+                cumulative.push(record_flow(&tx, &FlowEvent {
+                    chain_id: record.chain_id,
+                    block_number: Some(record.position.block_number),
+                    block_timestamp,
+                    block_hash: record.block_hash.clone(),
+                    tx_hash: record.tx_hash.clone(),
+                    log_index: Some(record.position.log_index),
+                    exchange,
+                    token: &record.token,
+                    inflow: *inflow,
+                    outflow: *outflow,
+                }).await?);
+            }
+        }
+
+        save_checkpoint(&tx, record.chain_id, &record.token, &record.position).await?;
+        tx.commit().await?;
+        Ok(Some(cumulative))
+    }
+
+    async fn advance_checkpoints(&self, chain_id: u64, tokens: &[String], position: Checkpoint) -> anyhow::Result<()> {
+        let mut client = self.client.lock().await;
+        let tx = client.transaction().await?;
+        for token in tokens {
+            tx.execute(
+                "INSERT INTO checkpoints (chain_id, token, block_number, log_index)
+                 VALUES ($1, $2, $3, $4)
+                 ON CONFLICT (chain_id, token) DO UPDATE SET
+                    block_number = excluded.block_number,
+                    log_index = excluded.log_index,
+                    last_updated = now()
+                 WHERE (excluded.block_number, excluded.log_index) > (checkpoints.block_number, checkpoints.log_index)",
+                &[&(chain_id as i64), token, &(position.block_number as i64), &(position.log_index as i64)],
+            ).await?;
+        }
+        tx.commit().await?;
+        Ok(())
+    }
+
+    async fn finalize_up_to(&self, chain_id: u64, block_number: u64) -> anyhow::Result<u64> {
+        Ok(self.client.lock().await.execute(
+            "UPDATE netflows SET finalized = true WHERE NOT finalized AND chain_id = $1 AND block_number <= $2",
+            &[&(chain_id as i64), &(block_number as i64)],
+        ).await?)
+    }
+
+    async fn token_metadata(&self, chain_id: u64, address: &str) -> anyhow::Result<Option<TokenMetadata>> {
+        let row = self.client.lock().await.query_opt(
+            "SELECT name, symbol, decimals FROM tokens WHERE chain_id = $1 AND address = $2",
+            &[&(chain_id as i64), &address],
+        ).await?;
+        Ok(row.map(|row| TokenMetadata { name: row.get(0), symbol: row.get(1), decimals: row.get::<_, i32>(2) as u8 }))
+    }
+
+    async fn store_token_metadata(&self, chain_id: u64, address: &str, metadata: &TokenMetadata) -> anyhow::Result<()> {
+        self.client.lock().await.execute(
+            "INSERT INTO tokens (chain_id, address, name, symbol, decimals) VALUES ($1, $2, $3, $4, $5)
+             ON CONFLICT (chain_id, address) DO UPDATE SET
+                 name = excluded.name, symbol = excluded.symbol, decimals = excluded.decimals",
+            &[&(chain_id as i64), &address, &metadata.name, &metadata.symbol, &(metadata.decimals as i32)],
+        ).await?;
+        Ok(())
+    }
+
+    async fn block_hash(&self, chain_id: u64, block_number: u64) -> anyhow::Result<Option<String>> {
+        let row = self.client.lock().await.query_opt(
+            "SELECT block_hash FROM blocks WHERE chain_id = $1 AND block_number = $2",
+            &[&(chain_id as i64), &(block_number as i64)],
+        ).await?;
+        Ok(row.map(|row| row.get(0)))
+    }
+
+    async fn latest_block_before(&self, chain_id: u64, block_number: u64) -> anyhow::Result<Option<(u64, String)>> {
+        let row = self.client.lock().await.query_opt(
+            "SELECT block_number, block_hash FROM blocks
+             WHERE chain_id = $1 AND block_number < $2
+             ORDER BY block_number DESC LIMIT 1",
+            &[&(chain_id as i64), &(block_number as i64)],
+        ).await?;
+        Ok(row.map(|row| (row.get::<_, i64>(0) as u64, row.get(1))))
+    }
+
+    async fn record_block(&self, chain_id: u64, header: &BlockHeader) -> anyhow::Result<()> {
+        let client = self.client.lock().await;
+        client.execute(
+            "INSERT INTO blocks (chain_id, block_number, block_hash, parent_hash, timestamp)
+             VALUES ($1, $2, $3, $4, $5)
+             ON CONFLICT (chain_id, block_number) DO UPDATE SET
+                 block_hash = excluded.block_hash, parent_hash = excluded.parent_hash, timestamp = excluded.timestamp",
+            &[&(chain_id as i64), &(header.number as i64), &header.hash, &header.parent_hash, &(header.timestamp as i64)],
+        ).await?;
+        client.execute(
+            "DELETE FROM blocks WHERE chain_id = $1 AND block_number < $2",
+            &[&(chain_id as i64), &(header.number.saturating_sub(BLOCK_HISTORY) as i64)],
+        ).await?;
+        Ok(())
+    }
+
+    async fn rollback_to(&self, chain_id: u64, fork_block: u64, detected_at: u64) -> anyhow::Result<()> {
+        let mut client = self.client.lock().await;
+        let tx = client.transaction().await?;
+        let (chain_id_param, fork_param) = (chain_id as i64, fork_block as i64);
+
+        revert_rollups_above(&tx, chain_id, fork_block).await?;
+        tx.execute("DELETE FROM netflows WHERE chain_id = $1 AND block_number > $2", &[&chain_id_param, &fork_param]).await?;
+        rebuild_balances(&tx, chain_id).await?;
+        tx.execute("DELETE FROM transactions WHERE chain_id = $1 AND block_number > $2", &[&chain_id_param, &fork_param]).await?;
+        tx.execute("DELETE FROM blocks WHERE chain_id = $1 AND block_number > $2", &[&chain_id_param, &fork_param]).await?;
+        tx.execute(
+            "UPDATE checkpoints SET block_number = $2, log_index = $3, last_updated = now()
+             WHERE chain_id = $1 AND block_number > $2",
+            &[&chain_id_param, &fork_param, &(END_OF_BLOCK as i64)],
+        ).await?;
+        tx.execute(
+            "INSERT INTO reorgs (chain_id, fork_block, detected_at_block) VALUES ($1, $2, $3)",
+            &[&chain_id_param, &fork_param, &(detected_at as i64)],
+        ).await?;
+
+        tx.commit().await?;
+        Ok(())
+    }
+
+    async fn balances(&self, chain_id: Option<u64>, at_block: Option<u64>) -> anyhow::Result<Vec<BalanceRow>> {
+        let client = self.client.lock().await;
+        let rows = client.query(
+            "SELECT chain_id, exchange, token, inflow, outflow,
+                    to_char(last_updated AT TIME ZONE 'UTC', 'YYYY-MM-DD HH24:MI:SS')
+             FROM balances
+             WHERE $1::BIGINT IS NULL OR chain_id = $1
+             ORDER BY chain_id, exchange, token",
+            &[&chain_id.map(|c| c as i64)],
+        ).await?;
+
+        let mut balances = Vec::new();
+        for row in rows {
+            let chain_id = row.get::<_, i64>(0) as u64;
+            let exchange: String = row.get(1);
+            let token: String = row.get(2);
+            let at_block = match at_block {
+                Some(block) => Some(totals_at_block(&client, chain_id, &exchange, &token, block).await?),
+                None => None,
+            };
+            balances.push(BalanceRow {
+                provisional: to_flow(Some((row.get(3), row.get(4))))?,
+                finalized: finalized_totals(&client, chain_id, &exchange, &token).await?,
+                at_block,
+                last_updated: row.get(5),
+                chain_id,
+                exchange,
+                token,
+            });
+        }
+        Ok(balances)
+    }
+
+    async fn buckets(&self, query: &BucketQuery) -> anyhow::Result<Vec<BucketRow>> {
+        let rows = self.client.lock().await.query(
+            "SELECT chain_id, token, bucket_start, inflow, outflow, transfer_count
+             FROM netflow_rollups
+             WHERE exchange = $1 AND bucket_size = $2
+               AND ($3::BIGINT IS NULL OR chain_id = $3) AND ($4::TEXT IS NULL OR token = $4)
+               AND ($5::BIGINT IS NULL OR bucket_start >= $5) AND ($6::BIGINT IS NULL OR bucket_start < $6)
+             ORDER BY chain_id, token, bucket_start",
+            &[
+                &query.exchange,
+                &query.bucket_size,
+                &query.chain_id.map(|c| c as i64),
+                &query.token,
+                &query.from.map(|t| t as i64),
+                &query.to.map(|t| t as i64),
+            ],
+        ).await?;
+
+        rows.into_iter()
+            .map(|row| {
+                Ok(BucketRow {
+                    chain_id: row.get::<_, i64>(0) as u64,
+                    token: row.get(1),
+                    bucket_start: row.get::<_, i64>(2) as u64,
+                    flow: to_flow(Some((row.get(3), row.get(4))))?,
+                    transfer_count: row.get::<_, i64>(5) as u64,
+                })
+            })
+            .collect()
+    }
+
+    async fn window(&self, query: &WindowQuery) -> anyhow::Result<Vec<WindowRow>> {
+        let rows = self.client.lock().await.query(
+            "SELECT chain_id, token, inflow, outflow
+             FROM netflows
+             WHERE exchange = $1 AND ($2::BIGINT IS NULL OR chain_id = $2) AND ($3::TEXT IS NULL OR token = $3)
+               AND ($4::BIGINT IS NULL OR block_number >= $4) AND ($5::BIGINT IS NULL OR block_number <= $5)
+               AND ($6::BIGINT IS NULL OR block_timestamp >= to_timestamp($6))
+               AND ($7::BIGINT IS NULL OR block_timestamp < to_timestamp($7))
+             ORDER BY chain_id, token, id",
+            &[
+                &query.exchange,
+                &query.chain_id.map(|c| c as i64),
+                &query.token,
+                &query.from_block.map(|b| b as i64),
+                &query.to_block.map(|b| b as i64),
+                &query.from_time.map(|t| t as i64),
+                &query.to_time.map(|t| t as i64),
+            ],
+        ).await?;
+        sum_window(rows.into_iter().map(|row| (row.get::<_, i64>(0) as u64, row.get(1), row.get(2), row.get(3))).collect())
+    }
+
+    async fn transfers(&self, query: &TransferQuery) -> anyhow::Result<Vec<TransferRow>> {
+        let rows = self.client.lock().await.query(
+            "SELECT chain_id, block_number, log_index, block_hash, tx_hash, token, from_address, to_address, amount,
+                    direction, exchange, counterparty_exchange, EXTRACT(EPOCH FROM block_timestamp)::BIGINT,
+                    to_char(ingested_at AT TIME ZONE 'UTC', 'YYYY-MM-DD HH24:MI:SS')
+             FROM transactions
+             WHERE ($1::BIGINT IS NULL OR chain_id = $1)
+               AND ($2::TEXT IS NULL OR exchange = $2 OR counterparty_exchange = $2)
+               AND ($3::TEXT IS NULL OR direction = $3)
+               AND ($4::TEXT IS NULL OR from_address = $4 OR to_address = $4)
+               -- Amounts are decimal strings without leading zeros, so compare length first
+               AND ($5::TEXT IS NULL OR LENGTH(amount) > LENGTH($5)
+                    OR (LENGTH(amount) = LENGTH($5) AND amount COLLATE \"C\" >= $5))
+               AND ($6::TEXT IS NULL OR LENGTH(amount) < LENGTH($6)
+                    OR (LENGTH(amount) = LENGTH($6) AND amount COLLATE \"C\" <= $6))
+               AND ($7::BIGINT IS NULL OR block_number >= $7) AND ($8::BIGINT IS NULL OR block_number <= $8)
+               AND ($9::BIGINT IS NULL OR (block_number, log_index, chain_id) > ($9, $10::BIGINT, $11::BIGINT))
+             ORDER BY block_number, log_index, chain_id
+             LIMIT $12",
+            &[
+                &query.chain_id.map(|c| c as i64),
+                &query.exchange,
+                &query.direction,
+                &query.address,
+                &query.min_amount.map(|a| a.to_string()),
+                &query.max_amount.map(|a| a.to_string()),
+                &query.from_block.map(|b| b as i64),
+                &query.to_block.map(|b| b as i64),
+                &query.after.map(|c| c.block_number as i64),
+                &query.after.map(|c| c.log_index as i64),
+                &query.after.map(|c| c.chain_id as i64),
+                &(query.limit as i64),
+            ],
+        ).await?;
+
+        rows.into_iter()
+            .map(|row| {
+                Ok(TransferRow {
+                    position: TransferCursor {
+                        chain_id: row.get::<_, i64>(0) as u64,
+                        block_number: row.get::<_, i64>(1) as u64,
+                        log_index: row.get::<_, i64>(2) as u64,
+                    },
+                    block_hash: row.get(3),
+                    tx_hash: row.get(4),
+                    token: row.get(5),
+                    from: row.get(6),
+                    to: row.get(7),
+                    amount: parse_amount(row.get(8))?,
+                    direction: row.get(9),
+                    exchange: row.get(10),
+                    counterparty_exchange: row.get(11),
+                    block_timestamp: row.get::<_, Option<i64>>(12).map(|t| t as u64),
+                    ingested_at: row.get(13),
+                })
+            })
+            .collect()
+    }
+
+    async fn netflow_seq(&self, chain_id: u64, position: Checkpoint) -> anyhow::Result<Option<u64>> {
+        let row = self.client.lock().await.query_one(
+            "SELECT MAX(id) FROM netflows WHERE chain_id = $1 AND (block_number, log_index) <= ($2, $3)",
+            &[&(chain_id as i64), &(position.block_number as i64), &(position.log_index as i64)],
+        ).await?;
+        Ok(row.get::<_, Option<i64>>(0).map(|s| s as u64))
+    }
+
+    async fn netflow_updates(&self, after_seq: u64, limit: u64) -> anyhow::Result<Vec<NetflowUpdate>> {
+        let rows = self.client.lock().await.query(
+            "SELECT id, chain_id, block_number, log_index, tx_hash, exchange, token, cumulative_netflow
+             FROM netflows
+             WHERE id > $1 AND block_number IS NOT NULL AND log_index IS NOT NULL
+             ORDER BY id
+             LIMIT $2",
+            &[&(after_seq as i64), &(limit as i64)],
+        ).await?;
+        Ok(rows.into_iter()
+            .map(|row| NetflowUpdate {
+                seq: row.get::<_, i64>(0) as u64,
+                chain_id: row.get::<_, i64>(1) as u64,
+                position: Checkpoint {
+                    block_number: row.get::<_, i64>(2) as u64,
+                    log_index: row.get::<_, i64>(3) as u64,
+                },
+                tx_hash: row.get(4),
+                exchange: row.get(5),
+                token: row.get(6),
+                cumulative_netflow: row.get(7),
+            })
+            .collect())
+    }
+
+    async fn reorgs(&self) -> anyhow::Result<(u64, Option<ReorgRow>)> {
+        let client = self.client.lock().await;
+        let count: i64 = client.query_one("SELECT COUNT(*) FROM reorgs", &[]).await?.get(0);
+        let last = client.query_opt(
+            "SELECT chain_id, fork_block, detected_at_block,
+                    to_char(detected_at AT TIME ZONE 'UTC', 'YYYY-MM-DD HH24:MI:SS')
+             FROM reorgs ORDER BY id DESC LIMIT 1",
+            &[],
+        ).await?;
+        Ok((count as u64, last.map(|row| ReorgRow {
+            chain_id: row.get::<_, i64>(0) as u64,
+            fork_block: row.get::<_, i64>(1) as u64,
+            detected_at_block: row.get::<_, i64>(2) as u64,
+            detected_at: row.get(3),
+        })))
+    }
+}
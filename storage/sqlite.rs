@@ -0,0 +1,961 @@
+// SQLite backend: a local file, one connection per listener and one for the API
+use async_trait::async_trait;
+use rusqlite::{Connection, OptionalExtension, TransactionBehavior, params};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use super::{advance_totals, check_schema_version, rollup_buckets, sum_window, to_flow, update_bucket, BalanceRow, BlockHeader, BucketQuery, BucketRow, Checkpoint, FlowEvent, LogRecord, NetflowUpdate, ReorgRow, Storage, TransferCursor, TransferQuery, TransferRecord, TransferRow, WindowQuery, WindowRow};
+use crate::amount::{Flow, SignedAmount, parse_amount};
+use crate::reorg::{BLOCK_HISTORY, END_OF_BLOCK};
+use crate::token::TokenMetadata;
+
+// Migration N brings the schema from version N - 1 to N. Append new ones; never edit applied ones.
+const MIGRATIONS: &[&str] = &[
+    // 1: the tables as first released. A database from before versioning has exactly these,
+    // so it adopts version 1 and takes every later migration.
+    "CREATE TABLE IF NOT EXISTS transactions (
+        id INTEGER PRIMARY KEY AUTOINCREMENT,
+        block_number BIGINT,
+        tx_hash TEXT,
+        from_address TEXT,
+        to_address TEXT,
+        amount TEXT,
+        timestamp DATETIME DEFAULT CURRENT_TIMESTAMP
+    );
+    CREATE TABLE IF NOT EXISTS netflows (
+        id INTEGER PRIMARY KEY AUTOINCREMENT,
+        exchange TEXT,
+        inflow TEXT,
+        outflow TEXT,
+        cumulative_netflow TEXT,
+        last_updated DATETIME DEFAULT CURRENT_TIMESTAMP
+    );",
+    // 2: position, token and classification of each transfer. Rows written before this keep
+    // a NULL chain_id and are left out of every query.
+    "ALTER TABLE transactions ADD COLUMN chain_id BIGINT;
+    ALTER TABLE transactions ADD COLUMN block_hash TEXT;
+    ALTER TABLE transactions ADD COLUMN log_index BIGINT;
+    ALTER TABLE transactions ADD COLUMN token TEXT;
+    -- deposit, withdrawal, internal or cross_exchange
+    ALTER TABLE transactions ADD COLUMN direction TEXT;
+    ALTER TABLE transactions ADD COLUMN exchange TEXT;
+    -- Receiving exchange of a cross_exchange transfer, where `exchange` is the sender
+    ALTER TABLE transactions ADD COLUMN counterparty_exchange TEXT;
+    -- On-chain time of the block; ingested_at is when the indexer wrote the row
+    ALTER TABLE transactions ADD COLUMN block_timestamp DATETIME;
+    ALTER TABLE transactions RENAME COLUMN timestamp TO ingested_at;
+    CREATE UNIQUE INDEX transactions_by_log ON transactions (chain_id, tx_hash, log_index);",
+    // 3: netflow per chain and token, with running totals and finality
+    "ALTER TABLE netflows ADD COLUMN chain_id BIGINT;
+    ALTER TABLE netflows ADD COLUMN block_number BIGINT;
+    ALTER TABLE netflows ADD COLUMN block_hash TEXT;
+    ALTER TABLE netflows ADD COLUMN tx_hash TEXT;
+    ALTER TABLE netflows ADD COLUMN log_index BIGINT;
+    ALTER TABLE netflows ADD COLUMN token TEXT;
+    -- Running totals for (chain_id, exchange, token) including this row, with cumulative_netflow
+    ALTER TABLE netflows ADD COLUMN cumulative_inflow TEXT;
+    ALTER TABLE netflows ADD COLUMN cumulative_outflow TEXT;
+    ALTER TABLE netflows ADD COLUMN finalized BOOLEAN NOT NULL DEFAULT 0;
+    ALTER TABLE netflows ADD COLUMN block_timestamp DATETIME;
+    CREATE INDEX netflows_by_exchange ON netflows (chain_id, exchange, token, id);",
+    // 4: resume positions and token metadata
+    "CREATE TABLE checkpoints (
+        chain_id BIGINT NOT NULL,
+        token TEXT NOT NULL,
+        block_number BIGINT NOT NULL,
+        log_index BIGINT NOT NULL,
+        last_updated DATETIME DEFAULT CURRENT_TIMESTAMP,
+        PRIMARY KEY (chain_id, token)
+    );
+    CREATE TABLE tokens (
+        chain_id BIGINT NOT NULL,
+        address TEXT NOT NULL,
+        name TEXT NOT NULL,
+        symbol TEXT NOT NULL,
+        decimals INTEGER NOT NULL,
+        PRIMARY KEY (chain_id, address)
+    );",
+    // 5: block headers for reorg detection, and the reorgs found
+    "CREATE TABLE blocks (
+        chain_id BIGINT NOT NULL,
+        block_number BIGINT NOT NULL,
+        block_hash TEXT NOT NULL,
+        parent_hash TEXT NOT NULL,
+        -- Unix seconds from the block header
+        timestamp BIGINT NOT NULL,
+        PRIMARY KEY (chain_id, block_number)
+    );
+    CREATE TABLE reorgs (
+        id INTEGER PRIMARY KEY AUTOINCREMENT,
+        chain_id BIGINT NOT NULL,
+        fork_block BIGINT NOT NULL,
+        detected_at_block BIGINT NOT NULL,
+        detected_at DATETIME DEFAULT CURRENT_TIMESTAMP
+    );",
+    // 6: current totals and time-bucketed netflow
+    "CREATE TABLE balances (
+        chain_id BIGINT NOT NULL,
+        exchange TEXT NOT NULL,
+        token TEXT NOT NULL,
+        inflow TEXT NOT NULL,
+        outflow TEXT NOT NULL,
+        netflow TEXT NOT NULL,
+        last_updated DATETIME DEFAULT CURRENT_TIMESTAMP,
+        PRIMARY KEY (chain_id, exchange, token)
+    );
+    CREATE TABLE netflow_rollups (
+        chain_id BIGINT NOT NULL,
+        exchange TEXT NOT NULL,
+        token TEXT NOT NULL,
+        -- 1m, 1h or 1d
+        bucket_size TEXT NOT NULL,
+        -- Unix seconds, aligned to the bucket size
+        bucket_start BIGINT NOT NULL,
+        inflow TEXT NOT NULL,
+        outflow TEXT NOT NULL,
+        transfer_count BIGINT NOT NULL,
+        PRIMARY KEY (chain_id, exchange, token, bucket_size, bucket_start)
+    );",
+    // 7: keyset pagination for /transfers
+    "CREATE INDEX transactions_by_position ON transactions (block_number, log_index, chain_id);",
+];
+
+pub struct SqliteStorage {
+    conn: Arc<Mutex<Connection>>,
+}
+
+impl SqliteStorage {
+    pub fn open(path: &str) -> anyhow::Result<Self> {
+        let storage = Self::connect(path)?;
+        migrate(&mut storage.conn.lock().unwrap())?;
+        Ok(storage)
+    }
+
+    // A further connection to a database `open` already migrated
+    pub fn connect(path: &str) -> anyhow::Result<Self> {
+        let conn = Connection::open(path)?;
+        // Each chain listener writes through its own connection, so wait out the others' locks
+        conn.busy_timeout(Duration::from_secs(5))?;
+        // WAL lets the API read while the chain listeners write
+        conn.pragma_update(None, "journal_mode", "WAL")?;
+        Ok(SqliteStorage { conn: Arc::new(Mutex::new(conn)) })
+    }
+
+    // Run blocking SQLite calls on tokio's blocking pool, so a slow disk or a wait on another
+    // connection's write lock never holds up the async workers
+    async fn run<T, F>(&self, work: F) -> anyhow::Result<T>
+    where
+        T: Send + 'static,
+        F: FnOnce(&mut Connection) -> anyhow::Result<T> + Send + 'static,
+    {
+        let conn = self.conn.clone();
+        tokio::task::spawn_blocking(move || work(&mut conn.lock().unwrap())).await?
+    }
+}
+
+// Apply pending migrations, each in its own transaction together with its version row
+fn migrate(conn: &mut Connection) -> anyhow::Result<()> {
+    conn.execute_batch(
+        "CREATE TABLE IF NOT EXISTS schema_version (
+            version INTEGER PRIMARY KEY,
+            applied_at DATETIME DEFAULT CURRENT_TIMESTAMP
+        );",
+    )?;
+    loop {
+        // IMMEDIATE takes the write lock up front, so concurrent openers apply each migration once
+        let tx = conn.transaction_with_behavior(TransactionBehavior::Immediate)?;
+        let version: u64 = tx.query_row("SELECT COALESCE(MAX(version), 0) FROM schema_version", [], |row| row.get(0))?;
+        check_schema_version(version, MIGRATIONS.len())?;
+        let Some(migration) = MIGRATIONS.get(version as usize) else {
+            return Ok(());
+        };
+        tx.execute_batch(migration)?;
+        tx.execute("INSERT INTO schema_version (version) VALUES (?1)", params![version + 1])?;
+        tx.commit()?;
+        println!("🗄️ Migrated SQLite schema to version {}", version + 1);
+    }
+}
+
+fn load_checkpoint(conn: &Connection, chain_id: u64, token: &str) -> rusqlite::Result<Option<Checkpoint>> {
+    conn.query_row(
+        "SELECT block_number, log_index FROM checkpoints WHERE chain_id = ?1 AND token = ?2",
+        params![chain_id as i64, token],
+        |row| Ok(Checkpoint {
+            block_number: row.get::<_, i64>(0)? as u64,
+            log_index: row.get::<_, i64>(1)? as u64,
+        }),
+    ).optional()
+}
+
+fn save_checkpoint(conn: &Connection, chain_id: u64, token: &str, checkpoint: &Checkpoint) -> rusqlite::Result<()> {
+    conn.execute(
+        "INSERT INTO checkpoints (chain_id, token, block_number, log_index)
+         VALUES (?1, ?2, ?3, ?4)
+         ON CONFLICT (chain_id, token) DO UPDATE SET
+            block_number = excluded.block_number,
+            log_index = excluded.log_index,
+            last_updated = CURRENT_TIMESTAMP",
+        params![chain_id as i64, token, checkpoint.block_number as i64, checkpoint.log_index as i64],
+    )?;
+    Ok(())
+}
+
+fn block_timestamp(conn: &Connection, chain_id: u64, block_number: u64) -> rusqlite::Result<Option<u64>> {
+    conn.query_row(
+        "SELECT timestamp FROM blocks WHERE chain_id = ?1 AND block_number = ?2",
+        params![chain_id as i64, block_number as i64],
+        |row| Ok(row.get::<_, i64>(0)? as u64),
+    ).optional()
+}
+
+// Store a classified transfer; false if it was already recorded
+fn record_transfer(
+    conn: &Connection,
+    record: &LogRecord,
+    transfer: &TransferRecord,
+    block_timestamp: Option<u64>,
+) -> rusqlite::Result<bool> {
+    let inserted = conn.execute(
+        "INSERT OR IGNORE INTO transactions (chain_id, block_number, block_hash, tx_hash, log_index, token,
+                                             from_address, to_address, amount, direction, exchange, counterparty_exchange,
+                                             block_timestamp)
+         VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12, datetime(?13, 'unixepoch'))",
+        params![
+            record.chain_id as i64,
+            record.position.block_number as i64,
+            record.block_hash,
+            record.tx_hash,
+            record.position.log_index as i64,
+            record.token,
+            transfer.from,
+            transfer.to,
+            transfer.amount.to_string(),
+            transfer.direction,
+            transfer.exchange,
+            transfer.counterparty,
+            block_timestamp.map(|t| t as i64),
+        ],
+    )?;
+    Ok(inserted > 0)
+}
+
+fn current_totals(conn: &Connection, chain_id: u64, exchange: &str, token: &str) -> anyhow::Result<Flow> {
+    let totals = conn.query_row(
+        "SELECT inflow, outflow FROM balances WHERE chain_id = ?1 AND exchange = ?2 AND token = ?3",
+        params![chain_id as i64, exchange, token],
+        |row| Ok((row.get(0)?, row.get(1)?)),
+    ).optional()?;
+    to_flow(totals)
+}
+
+fn finalized_totals(conn: &Connection, chain_id: u64, exchange: &str, token: &str) -> anyhow::Result<Flow> {
+    let totals = conn.query_row(
+        "SELECT cumulative_inflow, cumulative_outflow FROM netflows
+         WHERE chain_id = ?1 AND exchange = ?2 AND token = ?3 AND finalized = 1
+         ORDER BY id DESC LIMIT 1",
+        params![chain_id as i64, exchange, token],
+        |row| Ok((row.get(0)?, row.get(1)?)),
+    ).optional()?;
+    to_flow(totals)
+}
+
+fn totals_at_block(conn: &Connection, chain_id: u64, exchange: &str, token: &str, block_number: u64) -> anyhow::Result<Flow> {
+    let totals = conn.query_row(
+        "SELECT cumulative_inflow, cumulative_outflow FROM netflows
+         WHERE chain_id = ?1 AND exchange = ?2 AND token = ?3 AND block_number <= ?4
+         ORDER BY id DESC LIMIT 1",
+        params![chain_id as i64, exchange, token, block_number as i64],
+        |row| Ok((row.get(0)?, row.get(1)?)),
+    ).optional()?;
+    to_flow(totals)
+}
+
+// Insert a netflows row carrying the new running totals and advance the balance and rollups.
+// Call inside the transaction that applies the event so all of them move together.
+fn record_flow(conn: &Connection, event: &FlowEvent) -> anyhow::Result<SignedAmount> {
+    let (totals, cumulative) = advance_totals(current_totals(conn, event.chain_id, event.exchange, event.token)?, event)?;
+
+    conn.execute(
+        "INSERT INTO netflows (chain_id, block_number, block_hash, tx_hash, log_index, exchange, token, inflow, outflow,
+                               cumulative_inflow, cumulative_outflow, cumulative_netflow, block_timestamp)
+         VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12, datetime(?13, 'unixepoch'))",
+        params![
+            event.chain_id as i64,
+            event.block_number.map(|b| b as i64),
+            event.block_hash,
+            event.tx_hash,
+            event.log_index.map(|i| i as i64),
+            event.exchange,
+            event.token,
+            event.inflow.to_string(),
+            event.outflow.to_string(),
+            totals.inflow.to_string(),
+            totals.outflow.to_string(),
+            cumulative.to_string(),
+            event.block_timestamp.map(|t| t as i64),
+        ],
+    )?;
+    conn.execute(
+        "INSERT INTO balances (chain_id, exchange, token, inflow, outflow, netflow) VALUES (?1, ?2, ?3, ?4, ?5, ?6)
+         ON CONFLICT (chain_id, exchange, token) DO UPDATE SET
+             inflow = excluded.inflow, outflow = excluded.outflow, netflow = excluded.netflow,
+             last_updated = CURRENT_TIMESTAMP",
+        params![
+            event.chain_id as i64,
+            event.exchange,
+            event.token,
+            totals.inflow.to_string(),
+            totals.outflow.to_string(),
+            cumulative.to_string(),
+        ],
+    )?;
+    apply_rollups(conn, event, false)?;
+    Ok(cumulative)
+}
+
+// Add a netflows row to (or with `revert`, take it out of) the bucket of each size
+// its block timestamp falls in
+fn apply_rollups(conn: &Connection, event: &FlowEvent, revert: bool) -> anyhow::Result<()> {
+    for (bucket_size, bucket_start) in rollup_buckets(event) {
+        let key = params![event.chain_id as i64, event.exchange, event.token, bucket_size, bucket_start];
+
+        let stored = conn.query_row(
+            "SELECT inflow, outflow, transfer_count FROM netflow_rollups
+             WHERE chain_id = ?1 AND exchange = ?2 AND token = ?3 AND bucket_size = ?4 AND bucket_start = ?5",
+            key,
+            |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)),
+        ).optional()?;
+
+        match update_bucket(stored, event, revert)? {
+            None => {
+                conn.execute(
+                    "DELETE FROM netflow_rollups
+                     WHERE chain_id = ?1 AND exchange = ?2 AND token = ?3 AND bucket_size = ?4 AND bucket_start = ?5",
+                    key,
+                )?;
+            }
+            Some((flow, count)) => {
+                conn.execute(
+                    "INSERT OR REPLACE INTO netflow_rollups
+                     (chain_id, exchange, token, bucket_size, bucket_start, inflow, outflow, transfer_count)
+                     VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8)",
+                    params![
+                        event.chain_id as i64,
+                        event.exchange,
+                        event.token,
+                        bucket_size,
+                        bucket_start,
+                        flow.inflow.to_string(),
+                        flow.outflow.to_string(),
+                        count,
+                    ],
+                )?;
+            }
+        }
+    }
+    Ok(())
+}
+
+// Take the netflows rows above `fork_block` back out of their buckets; call before deleting them
+fn revert_rollups_above(conn: &Connection, chain_id: u64, fork_block: u64) -> anyhow::Result<()> {
+    let mut stmt = conn.prepare(
+        "SELECT exchange, token, inflow, outflow, CAST(strftime('%s', block_timestamp) AS INTEGER) FROM netflows
+         WHERE chain_id = ?1 AND block_number > ?2 AND block_timestamp IS NOT NULL"
+    )?;
+    let rows = stmt.query_map(params![chain_id as i64, fork_block as i64], |row| {
+        Ok((row.get::<_, String>(0)?, row.get::<_, String>(1)?, row.get::<_, String>(2)?, row.get::<_, String>(3)?, row.get::<_, i64>(4)?))
+    })?.collect::<rusqlite::Result<Vec<_>>>()?;
+
+    for (exchange, token, inflow, outflow, timestamp) in rows {
+        let event = FlowEvent {
+            chain_id,
+            block_number: None,
+            block_timestamp: Some(timestamp as u64),
+            block_hash: None,
+            tx_hash: None,
+            log_index: None,
+            exchange: &exchange,
+            token: &token,
+            inflow: parse_amount(&inflow)?,
+            outflow: parse_amount(&outflow)?,
+        };
+        apply_rollups(conn, &event, true)?;
+    }
+    Ok(())
+}
+
+// Reset a chain's balances to its latest remaining netflows rows, after a rollback deleted newer ones
+fn rebuild_balances(conn: &Connection, chain_id: u64) -> rusqlite::Result<()> {
+    conn.execute("DELETE FROM balances WHERE chain_id = ?1", params![chain_id as i64])?;
+    conn.execute(
+        "INSERT INTO balances (chain_id, exchange, token, inflow, outflow, netflow, last_updated)
+         SELECT chain_id, exchange, token, cumulative_inflow, cumulative_outflow, cumulative_netflow, last_updated
+         FROM netflows n
+         WHERE chain_id = ?1 AND id = (
+             SELECT MAX(id) FROM netflows WHERE chain_id = n.chain_id AND exchange = n.exchange AND token = n.token
+         )",
+        params![chain_id as i64],
+    )?;
+    Ok(())
+}
+
+#[async_trait]
+impl Storage for SqliteStorage {
+    async fn checkpoint(&self, chain_id: u64, token: &str) -> anyhow::Result<Option<Checkpoint>> {
+        let token = token.to_string();
+        self.run(move |conn| Ok(load_checkpoint(conn, chain_id, &token)?)).await
+    }
+
+    async fn apply_log(&self, record: &LogRecord) -> anyhow::Result<Option<Vec<SignedAmount>>> {
+        let record = record.clone();
+        self.run(move |conn| {
+            // IMMEDIATE takes the write lock before the checkpoint read; a deferred transaction
+            // cannot upgrade to a write while another listener's connection is writing
+            let tx = conn.transaction_with_behavior(TransactionBehavior::Immediate)?;
+
+            // Skip logs at or before the checkpoint (re-fetched after a restart or resubscription)
+            if let Some(checkpoint) = load_checkpoint(&tx, record.chain_id, &record.token)? {
+                let position = (record.position.block_number, record.position.log_index);
+                if position <= (checkpoint.block_number, checkpoint.log_index) {
+                    return Ok(None);
+                }
+            }
+
+            let block_timestamp = block_timestamp(&tx, record.chain_id, record.position.block_number)?;
+
+            // The unique (chain_id, tx_hash, log_index) key catches a transfer applied before
+            let is_new = match &record.transfer {
+                Some(transfer) => record_transfer(&tx, &record, transfer, block_timestamp)?,
+                None => true,
+            };
+
+            let mut cumulative = Vec::new();
+            if is_new {
+                for (exchange, inflow, outflow) in &record.flows {
+                    cumulative.push(record_flow(&tx, &FlowEvent {
+                        chain_id: record.chain_id,
+                        block_number: Some(record.position.block_number),
+                        block_timestamp,
+                        block_hash: record.block_hash.clone(),
+                        tx_hash: record.tx_hash.clone(),
+                        log_index: Some(record.position.log_index),
+                        exchange,
+                        token: &record.token,
+                        inflow: *inflow,
+                        outflow: *outflow,
+                    })?);
+                }
+            }
+
+            save_checkpoint(&tx, record.chain_id, &record.token, &record.position)?;
+            tx.commit()?;
+            Ok(Some(cumulative))
+        }).await
+    }
+
+    async fn advance_checkpoints(&self, chain_id: u64, tokens: &[String], position: Checkpoint) -> anyhow::Result<()> {
+        let tokens = tokens.to_vec();
+        self.run(move |conn| {
+            let tx = conn.transaction_with_behavior(TransactionBehavior::Immediate)?;
+            for token in &tokens {
+                tx.execute(
+                    "INSERT INTO checkpoints (chain_id, token, block_number, log_index)
+                     VALUES (?1, ?2, ?3, ?4)
+                     ON CONFLICT (chain_id, token) DO UPDATE SET
+                        block_number = excluded.block_number,
+                        log_index = excluded.log_index,
+                        last_updated = CURRENT_TIMESTAMP
+                     WHERE (excluded.block_number, excluded.log_index) > (checkpoints.block_number, checkpoints.log_index)",
+                    params![chain_id as i64, token, position.block_number as i64, position.log_index as i64],
+                )?;
+            }
+            tx.commit()?;
+            Ok(())
+        }).await
+    }
+
+    async fn finalize_up_to(&self, chain_id: u64, block_number: u64) -> anyhow::Result<u64> {
+        self.run(move |conn| {
+            let updated = conn.execute(
+                "UPDATE netflows SET finalized = 1 WHERE finalized = 0 AND chain_id = ?1 AND block_number <= ?2",
+                params![chain_id as i64, block_number as i64],
+            )?;
+            Ok(updated as u64)
+        }).await
+    }
+
+    async fn token_metadata(&self, chain_id: u64, address: &str) -> anyhow::Result<Option<TokenMetadata>> {
+        let address = address.to_string();
+        self.run(move |conn| {
+            Ok(conn.query_row(
+                "SELECT name, symbol, decimals FROM tokens WHERE chain_id = ?1 AND address = ?2",
+                params![chain_id as i64, address],
+                |row| Ok(TokenMetadata { name: row.get(0)?, symbol: row.get(1)?, decimals: row.get(2)? }),
+            ).optional()?)
+        }).await
+    }
+
+    async fn store_token_metadata(&self, chain_id: u64, address: &str, metadata: &TokenMetadata) -> anyhow::Result<()> {
+        let (address, metadata) = (address.to_string(), metadata.clone());
+        self.run(move |conn| {
+            conn.execute(
+                "INSERT OR REPLACE INTO tokens (chain_id, address, name, symbol, decimals) VALUES (?1, ?2, ?3, ?4, ?5)",
+                params![chain_id as i64, address, metadata.name, metadata.symbol, metadata.decimals],
+            )?;
+            Ok(())
+        }).await
+    }
+
+    async fn block_hash(&self, chain_id: u64, block_number: u64) -> anyhow::Result<Option<String>> {
+        self.run(move |conn| {
+            Ok(conn.query_row(
+                "SELECT block_hash FROM blocks WHERE chain_id = ?1 AND block_number = ?2",
+                params![chain_id as i64, block_number as i64],
+                |row| row.get(0),
+            ).optional()?)
+        }).await
+    }
+
+    async fn latest_block_before(&self, chain_id: u64, block_number: u64) -> anyhow::Result<Option<(u64, String)>> {
+        self.run(move |conn| {
+            Ok(conn.query_row(
+                "SELECT block_number, block_hash FROM blocks
+                 WHERE chain_id = ?1 AND block_number < ?2
+                 ORDER BY block_number DESC LIMIT 1",
+                params![chain_id as i64, block_number as i64],
+                |row| Ok((row.get::<_, i64>(0)? as u64, row.get(1)?)),
+            ).optional()?)
+        }).await
+    }
+
+    async fn record_block(&self, chain_id: u64, header: &BlockHeader) -> anyhow::Result<()> {
+        let header = header.clone();
+        self.run(move |conn| {
+            conn.execute(
+                "INSERT OR REPLACE INTO blocks (chain_id, block_number, block_hash, parent_hash, timestamp)
+                 VALUES (?1, ?2, ?3, ?4, ?5)",
+                params![chain_id as i64, header.number as i64, header.hash, header.parent_hash, header.timestamp as i64],
+            )?;
+            conn.execute(
+                "DELETE FROM blocks WHERE chain_id = ?1 AND block_number < ?2",
+                params![chain_id as i64, header.number.saturating_sub(BLOCK_HISTORY) as i64],
+            )?;
+            Ok(())
+        }).await
+    }
+
+    async fn rollback_to(&self, chain_id: u64, fork_block: u64, detected_at: u64) -> anyhow::Result<()> {
+        self.run(move |conn| {
+            let tx = conn.transaction_with_behavior(TransactionBehavior::Immediate)?;
+
+            revert_rollups_above(&tx, chain_id, fork_block)?;
+            tx.execute(
+                "DELETE FROM netflows WHERE chain_id = ?1 AND block_number > ?2",
+                params![chain_id as i64, fork_block as i64],
+            )?;
+            rebuild_balances(&tx, chain_id)?;
+            tx.execute(
+                "DELETE FROM transactions WHERE chain_id = ?1 AND block_number > ?2",
+                params![chain_id as i64, fork_block as i64],
+            )?;
+            tx.execute(
+                "DELETE FROM blocks WHERE chain_id = ?1 AND block_number > ?2",
+                params![chain_id as i64, fork_block as i64],
+            )?;
+            tx.execute(
+                "UPDATE checkpoints SET block_number = ?2, log_index = ?3, last_updated = CURRENT_TIMESTAMP
+                 WHERE chain_id = ?1 AND block_number > ?2",
+                params![chain_id as i64, fork_block as i64, END_OF_BLOCK as i64],
+            )?;
+            tx.execute(
+                "INSERT INTO reorgs (chain_id, fork_block, detected_at_block) VALUES (?1, ?2, ?3)",
+                params![chain_id as i64, fork_block as i64, detected_at as i64],
+            )?;
+
+            tx.commit()?;
+            Ok(())
+        }).await
+    }
+
+    async fn balances(&self, chain_id: Option<u64>, at_block: Option<u64>) -> anyhow::Result<Vec<BalanceRow>> {
+        self.run(move |conn| {
+            let mut stmt = conn.prepare(
+                "SELECT chain_id, exchange, token, inflow, outflow, last_updated FROM balances
+                 WHERE ?1 IS NULL OR chain_id = ?1
+                 ORDER BY chain_id, exchange, token"
+            )?;
+            let rows = stmt.query_map(params![chain_id.map(|c| c as i64)], |row| {
+                Ok((
+                    row.get::<_, i64>(0)? as u64,
+                    row.get::<_, String>(1)?,
+                    row.get::<_, String>(2)?,
+                    (row.get::<_, String>(3)?, row.get::<_, String>(4)?),
+                    row.get::<_, String>(5)?,
+                ))
+            })?.collect::<rusqlite::Result<Vec<_>>>()?;
+
+            let mut balances = Vec::new();
+            for (chain_id, exchange, token, totals, last_updated) in rows {
+                balances.push(BalanceRow {
+                    provisional: to_flow(Some(totals))?,
+                    finalized: finalized_totals(conn, chain_id, &exchange, &token)?,
+                    at_block: at_block.map(|b| totals_at_block(conn, chain_id, &exchange, &token, b)).transpose()?,
+                    chain_id,
+                    exchange,
+                    token,
+                    last_updated,
+                });
+            }
+            Ok(balances)
+        }).await
+    }
+
+    async fn buckets(&self, query: &BucketQuery) -> anyhow::Result<Vec<BucketRow>> {
+        let query = query.clone();
+        self.run(move |conn| {
+            let mut stmt = conn.prepare(
+                "SELECT chain_id, token, bucket_start, inflow, outflow, transfer_count
+                 FROM netflow_rollups
+                 WHERE exchange = ?1 AND bucket_size = ?2
+                   AND (?3 IS NULL OR chain_id = ?3) AND (?4 IS NULL OR token = ?4)
+                   AND (?5 IS NULL OR bucket_start >= ?5) AND (?6 IS NULL OR bucket_start < ?6)
+                 ORDER BY chain_id, token, bucket_start"
+            )?;
+            let rows = stmt.query_map(
+                params![
+                    query.exchange,
+                    query.bucket_size,
+                    query.chain_id.map(|c| c as i64),
+                    query.token,
+                    query.from.map(|t| t as i64),
+                    query.to.map(|t| t as i64),
+                ],
+                |row| {
+                    Ok((
+                        row.get::<_, i64>(0)? as u64,
+                        row.get::<_, String>(1)?,
+                        row.get::<_, i64>(2)? as u64,
+                        (row.get::<_, String>(3)?, row.get::<_, String>(4)?),
+                        row.get::<_, i64>(5)? as u64,
+                    ))
+                },
+            )?.collect::<rusqlite::Result<Vec<_>>>()?;
+
+            rows.into_iter()
+                .map(|(chain_id, token, bucket_start, totals, transfer_count)| {
+                    Ok(BucketRow { chain_id, token, bucket_start, flow: to_flow(Some(totals))?, transfer_count })
+                })
+                .collect()
+        }).await
+    }
+
+    async fn window(&self, query: &WindowQuery) -> anyhow::Result<Vec<WindowRow>> {
+        let query = query.clone();
+        self.run(move |conn| {
+            let mut stmt = conn.prepare(
+                "SELECT chain_id, token, inflow, outflow
+                 FROM netflows
+                 WHERE exchange = ?1 AND chain_id IS NOT NULL AND (?2 IS NULL OR chain_id = ?2) AND (?3 IS NULL OR token = ?3)
+                   AND (?4 IS NULL OR block_number >= ?4) AND (?5 IS NULL OR block_number <= ?5)
+                   AND (?6 IS NULL OR block_timestamp >= datetime(?6, 'unixepoch'))
+                   AND (?7 IS NULL OR block_timestamp < datetime(?7, 'unixepoch'))
+                 ORDER BY chain_id, token, id"
+            )?;
+            let rows = stmt.query_map(
+                params![
+                    query.exchange,
+                    query.chain_id.map(|c| c as i64),
+                    query.token,
+                    query.from_block.map(|b| b as i64),
+                    query.to_block.map(|b| b as i64),
+                    query.from_time.map(|t| t as i64),
+                    query.to_time.map(|t| t as i64),
+                ],
+                |row| Ok((row.get::<_, i64>(0)? as u64, row.get(1)?, row.get(2)?, row.get(3)?)),
+            )?.collect::<rusqlite::Result<Vec<_>>>()?;
+            sum_window(rows)
+        }).await
+    }
+
+    async fn transfers(&self, query: &TransferQuery) -> anyhow::Result<Vec<TransferRow>> {
+        let query = query.clone();
+        self.run(move |conn| {
+            let mut stmt = conn.prepare(
+                "SELECT chain_id, block_number, log_index, block_hash, tx_hash, token, from_address, to_address, amount,
+                        direction, exchange, counterparty_exchange, CAST(strftime('%s', block_timestamp) AS INTEGER), ingested_at
+                 FROM transactions
+                 WHERE chain_id IS NOT NULL AND (?1 IS NULL OR chain_id = ?1)
+                   AND (?2 IS NULL OR exchange = ?2 OR counterparty_exchange = ?2)
+                   AND (?3 IS NULL OR direction = ?3)
+                   AND (?4 IS NULL OR from_address = ?4 OR to_address = ?4)
+                   -- Amounts are decimal strings without leading zeros, so compare length first
+                   AND (?5 IS NULL OR LENGTH(amount) > LENGTH(?5) OR (LENGTH(amount) = LENGTH(?5) AND amount >= ?5))
+                   AND (?6 IS NULL OR LENGTH(amount) < LENGTH(?6) OR (LENGTH(amount) = LENGTH(?6) AND amount <= ?6))
+                   AND (?7 IS NULL OR block_number >= ?7) AND (?8 IS NULL OR block_number <= ?8)
+                   AND (?9 IS NULL OR (block_number, log_index, chain_id) > (?9, ?10, ?11))
+                 ORDER BY block_number, log_index, chain_id
+                 LIMIT ?12"
+            )?;
+            let rows = stmt.query_map(
+                params![
+                    query.chain_id.map(|c| c as i64),
+                    query.exchange,
+                    query.direction,
+                    query.address,
+                    query.min_amount.map(|a| a.to_string()),
+                    query.max_amount.map(|a| a.to_string()),
+                    query.from_block.map(|b| b as i64),
+                    query.to_block.map(|b| b as i64),
+                    query.after.map(|c| c.block_number as i64),
+                    query.after.map(|c| c.log_index as i64),
+                    query.after.map(|c| c.chain_id as i64),
+                    query.limit as i64,
+                ],
+                |row| {
+                    Ok((
+                        TransferCursor {
+                            chain_id: row.get::<_, i64>(0)? as u64,
+                            block_number: row.get::<_, i64>(1)? as u64,
+                            log_index: row.get::<_, i64>(2)? as u64,
+                        },
+                        (row.get(3)?, row.get(4)?, row.get(5)?, row.get(6)?, row.get(7)?, row.get::<_, String>(8)?),
+                        (row.get(9)?, row.get(10)?, row.get(11)?, row.get::<_, Option<i64>>(12)?, row.get(13)?),
+                    ))
+                },
+            )?.collect::<rusqlite::Result<Vec<_>>>()?;
+
+            rows.into_iter()
+                .map(|(position, (block_hash, tx_hash, token, from, to, amount), (direction, exchange, counterparty_exchange, block_timestamp, ingested_at))| {
+                    Ok(TransferRow {
+                        position,
+                        block_hash,
+                        tx_hash,
+                        token,
+                        from,
+                        to,
+                        amount: parse_amount(&amount)?,
+                        direction,
+                        exchange,
+                        counterparty_exchange,
+                        block_timestamp: block_timestamp.map(|t| t as u64),
+                        ingested_at,
+                    })
+                })
+                .collect()
+        }).await
+    }
+
+    async fn netflow_seq(&self, chain_id: u64, position: Checkpoint) -> anyhow::Result<Option<u64>> {
+        self.run(move |conn| {
+            let seq: Option<i64> = conn.query_row(
+                "SELECT MAX(id) FROM netflows WHERE chain_id = ?1 AND (block_number, log_index) <= (?2, ?3)",
+                params![chain_id as i64, position.block_number as i64, position.log_index as i64],
+                |row| row.get(0),
+            )?;
+            Ok(seq.map(|s| s as u64))
+        }).await
+    }
+
+    async fn netflow_updates(&self, after_seq: u64, limit: u64) -> anyhow::Result<Vec<NetflowUpdate>> {
+        self.run(move |conn| {
+            let mut stmt = conn.prepare(
+                "SELECT id, chain_id, block_number, log_index, tx_hash, exchange, token, cumulative_netflow
+                 FROM netflows
+                 WHERE id > ?1 AND chain_id IS NOT NULL AND block_number IS NOT NULL AND log_index IS NOT NULL
+                 ORDER BY id
+                 LIMIT ?2"
+            )?;
+            let rows = stmt.query_map(params![after_seq as i64, limit as i64], |row| {
+                Ok(NetflowUpdate {
+                    seq: row.get::<_, i64>(0)? as u64,
+                    chain_id: row.get::<_, i64>(1)? as u64,
+                    position: Checkpoint {
+                        block_number: row.get::<_, i64>(2)? as u64,
+                        log_index: row.get::<_, i64>(3)? as u64,
+                    },
+                    tx_hash: row.get(4)?,
+                    exchange: row.get(5)?,
+                    token: row.get(6)?,
+                    cumulative_netflow: row.get(7)?,
+                })
+            })?.collect::<rusqlite::Result<Vec<_>>>()?;
+            Ok(rows)
+        }).await
+    }
+
+    async fn reorgs(&self) -> anyhow::Result<(u64, Option<ReorgRow>)> {
+        self.run(|conn| {
+            let count: i64 = conn.query_row("SELECT COUNT(*) FROM reorgs", [], |row| row.get(0))?;
+            let last = conn.query_row(
+                "SELECT chain_id, fork_block, detected_at_block, detected_at
+                 FROM reorgs ORDER BY id DESC LIMIT 1",
+                [],
+                |row| {
+                    Ok(ReorgRow {
+                        chain_id: row.get::<_, i64>(0)? as u64,
+                        fork_block: row.get::<_, i64>(1)? as u64,
+                        detected_at_block: row.get::<_, i64>(2)? as u64,
+                        detected_at: row.get(3)?,
+                    })
+                },
+            ).optional()?;
+            Ok((count as u64, last))
+        }).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ethers::types::U256;
+    use crate::storage::{LogRecord, TransferRecord, WindowQuery};
+    use std::path::PathBuf;
+
+    // A database file of its own per test, removed with its WAL files afterwards
+    struct TempDb(PathBuf);
+
+    impl TempDb {
+        fn new(name: &str) -> Self {
+            let db = TempDb(std::env::temp_dir().join(format!("netflow-{}-{}.db", name, std::process::id())));
+            db.remove();
+            db
+        }
+
+        fn path(&self) -> &str {
+            self.0.to_str().unwrap()
+        }
+
+        fn remove(&self) {
+            for suffix in ["", "-wal", "-shm"] {
+                let _ = std::fs::remove_file(format!("{}{}", self.path(), suffix));
+            }
+        }
+    }
+
+    impl Drop for TempDb {
+        fn drop(&mut self) {
+            self.remove();
+        }
+    }
+
+    fn schema_version(storage: &SqliteStorage) -> usize {
+        let conn = storage.conn.lock().unwrap();
+        conn.query_row("SELECT MAX(version) FROM schema_version", [], |row| row.get(0)).unwrap()
+    }
+
+    fn deposit(token: &str, block_number: u64, amount: u64) -> LogRecord {
+        LogRecord {
+            chain_id: 137,
+            token: token.to_string(),
+            position: Checkpoint { block_number, log_index: 0 },
+            block_hash: Some(format!("0xb{}", block_number)),
+            tx_hash: Some(format!("0xt{}", block_number)),
+            transfer: Some(TransferRecord {
+                from: "0xaa".to_string(),
+                to: "0xbb".to_string(),
+                amount: U256::from(amount),
+                direction: "deposit",
+                exchange: "binance".to_string(),
+                counterparty: None,
+            }),
+            flows: vec![("binance".to_string(), U256::from(amount), U256::zero())],
+        }
+    }
+
+    #[tokio::test]
+    async fn migrates_database_created_before_versioning() {
+        let db = TempDb::new("baseline");
+        let conn = Connection::open(db.path()).unwrap();
+        conn.execute_batch(MIGRATIONS[0]).unwrap();
+        conn.execute(
+            "INSERT INTO transactions (block_number, tx_hash, from_address, to_address, amount) VALUES (1, '0x1', '0xa', '0xb', '5')",
+            [],
+        ).unwrap();
+        conn.execute(
+            "INSERT INTO netflows (exchange, inflow, outflow, cumulative_netflow) VALUES ('binance', '1000', '200', '800')",
+            [],
+        ).unwrap();
+        drop(conn);
+
+        let storage = SqliteStorage::open(db.path()).unwrap();
+        assert_eq!(schema_version(&storage), MIGRATIONS.len());
+
+        let cumulative = storage.apply_log(&deposit("0xtoken", 10, 7)).await.unwrap().unwrap();
+        assert_eq!(cumulative, vec![SignedAmount::net(U256::from(7), U256::zero())]);
+
+        // Rows from before the migration have no chain and stay out of the results
+        let window = storage.window(&WindowQuery {
+            exchange: "binance".to_string(),
+            chain_id: None,
+            token: None,
+            from_block: None,
+            to_block: None,
+            from_time: None,
+            to_time: None,
+        }).await.unwrap();
+        assert_eq!(window.len(), 1);
+        assert_eq!(window[0].flow.inflow, U256::from(7));
+        assert_eq!(window[0].transfer_count, 1);
+    }
+
+    #[tokio::test]
+    async fn reopening_keeps_data_and_version() {
+        let db = TempDb::new("reopen");
+        let storage = SqliteStorage::open(db.path()).unwrap();
+        storage.apply_log(&deposit("0xtoken", 10, 7)).await.unwrap();
+        drop(storage);
+
+        let storage = SqliteStorage::open(db.path()).unwrap();
+        assert_eq!(schema_version(&storage), MIGRATIONS.len());
+        // Applied before, so skipped
+        assert!(storage.apply_log(&deposit("0xtoken", 10, 7)).await.unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn scanned_blocks_advance_quiet_tokens_only() {
+        let db = TempDb::new("advance");
+        let storage = SqliteStorage::open(db.path()).unwrap();
+        storage.apply_log(&deposit("0xbusy", 20, 7)).await.unwrap();
+
+        let scanned = Checkpoint { block_number: 15, log_index: END_OF_BLOCK };
+        storage.advance_checkpoints(137, &["0xbusy".to_string(), "0xquiet".to_string()], scanned).await.unwrap();
+
+        let busy = storage.checkpoint(137, "0xbusy").await.unwrap().unwrap();
+        assert_eq!((busy.block_number, busy.log_index), (20, 0));
+        let quiet = storage.checkpoint(137, "0xquiet").await.unwrap().unwrap();
+        assert_eq!((quiet.block_number, quiet.log_index), (15, END_OF_BLOCK));
+    }
+
+    #[tokio::test]
+    async fn listeners_write_concurrently() {
+        let db = TempDb::new("concurrent");
+        let first = SqliteStorage::open(db.path()).unwrap();
+        let second = SqliteStorage::connect(db.path()).unwrap();
+
+        // Each listener has its own connection and its own tokens
+        async fn write(storage: &SqliteStorage, token: &str) {
+            for block_number in 1..=300 {
+                let mut record = deposit(token, block_number, 1);
+                record.tx_hash = Some(format!("{}-{}", token, block_number));
+                storage.apply_log(&record).await.unwrap();
+            }
+        }
+        tokio::join!(write(&first, "0xfirst"), write(&second, "0xsecond"));
+
+        let count: i64 = first.conn.lock().unwrap()
+            .query_row("SELECT COUNT(*) FROM netflows", [], |row| row.get(0))
+            .unwrap();
+        assert_eq!(count, 600);
+    }
+
+    #[test]
+    fn refuses_newer_database() {
+        let db = TempDb::new("newer");
+        drop(SqliteStorage::open(db.path()).unwrap());
+        let conn = Connection::open(db.path()).unwrap();
+        conn.execute("INSERT INTO schema_version (version) VALUES (?1)", params![MIGRATIONS.len() as i64 + 1]).unwrap();
+        drop(conn);
+
+        assert!(SqliteStorage::open(db.path()).is_err());
+    }
+}
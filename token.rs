@@ -0,0 +1,65 @@
+// ERC-20 token metadata discovery
+use ethers::prelude::*;
+use std::sync::Arc;
+
+use crate::storage::Storage;
+use crate::to_hex;
+
+abigen!(
+    Erc20,
+    r#"[
+        function name() external view returns (string)
+        function symbol() external view returns (string)
+        function decimals() external view returns (uint8)
+    ]"#
+);
+
+#[derive(Clone)]
+pub struct TokenMetadata {
+    pub name: String,
+    pub symbol: String,
+    pub decimals: u8,
+}
+
+impl TokenMetadata {
+    pub fn to_json(&self, address: &str) -> serde_json::Value {
+        serde_json::json!({
+            "address": address,
+            "name": self.name,
+            "symbol": self.symbol,
+            "decimals": self.decimals,
+        })
+    }
+}
+
+async fn fetch_metadata<M: Middleware + 'static>(client: Arc<M>, address: Address) -> anyhow::Result<TokenMetadata> {
+    let token = Erc20::new(address, client);
+    Ok(TokenMetadata {
+        name: token.name().call().await?,
+        symbol: token.symbol().call().await?,
+        decimals: token.decimals().call().await?,
+    })
+}
+
+// Fetch and store name/symbol/decimals the first time a token is seen.
+// Failures are only logged: amounts are still indexed, just without a decimal-scaled view.
+pub async fn ensure_metadata<M: Middleware + 'static>(
+    client: Arc<M>,
+    storage: &dyn Storage,
+    chain_id: u64,
+    address: Address,
+) -> anyhow::Result<()> {
+    let address_hex = to_hex(address.as_bytes());
+    if storage.token_metadata(chain_id, &address_hex).await?.is_some() {
+        return Ok(());
+    }
+
+    match fetch_metadata(client, address).await {
+        Ok(metadata) => {
+            println!("🪙 Token {}: {} ({}), {} decimals", address_hex, metadata.name, metadata.symbol, metadata.decimals);
+            storage.store_token_metadata(chain_id, &address_hex, &metadata).await?;
+        }
+        Err(e) => println!("⚠️ Could not read ERC-20 metadata for {}: {:#}", address_hex, e),
+    }
+    Ok(())
+}
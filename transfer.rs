@@ -0,0 +1,138 @@
+// Decoding of token transfer logs into one shape for classification
+use ethers::types::{Address, Filter, H160, H256, Log, U256};
+use ethers::utils::keccak256;
+
+const TRANSFER_EVENT: &str = "Transfer(address,address,uint256)";
+
+// Emitted by Polygon's native token contract for every native POL value move,
+// including plain value transfers between accounts
+const LOG_TRANSFER_EVENT: &str = "LogTransfer(address,address,address,uint256,uint256,uint256,uint256,uint256)";
+
+// Polygon PoS native token (MRC20) system contract, 0x...1010
+const NATIVE_TOKEN: Address = H160([0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0x10, 0x10]);
+
+pub struct TokenTransfer {
+    pub from: Address,
+    pub to: Address,
+    pub amount: U256,
+}
+
+pub enum Decoded {
+    Transfer(TokenTransfer),
+    // A log the filter matched but that is not counted
+    Ignored,
+    Malformed,
+}
+
+// ERC20 Transfer logs for all tracked tokens, plus LogTransfer for the native token
+pub fn transfer_filter(tokens: &[Address]) -> Filter {
+    Filter::new().address(tokens.to_vec()).events([TRANSFER_EVENT, LOG_TRANSFER_EVENT])
+}
+
+pub fn decode_transfer(log: &Log) -> Decoded {
+    let Some(topic0) = log.topics.first() else {
+        return Decoded::Malformed;
+    };
+    let native = log.address == NATIVE_TOKEN;
+
+    if *topic0 == event_topic(TRANSFER_EVENT) {
+        // The native contract's ERC20-style Transfer duplicates its LogTransfer
+        if native {
+            return Decoded::Ignored;
+        }
+        // Two indexed addresses and one uint256
+        if log.topics.len() != 3 || log.data.len() != 32 {
+            return Decoded::Malformed;
+        }
+        return Decoded::Transfer(TokenTransfer {
+            from: topic_address(&log.topics[1]),
+            to: topic_address(&log.topics[2]),
+            amount: U256::from_big_endian(&log.data),
+        });
+    }
+
+    if native && *topic0 == event_topic(LOG_TRANSFER_EVENT) {
+        // Indexed token, from, to; data holds amount, input1, input2, output1, output2
+        if log.topics.len() != 4 || log.data.len() != 5 * 32 {
+            return Decoded::Malformed;
+        }
+        return Decoded::Transfer(TokenTransfer {
+            from: topic_address(&log.topics[2]),
+            to: topic_address(&log.topics[3]),
+            amount: U256::from_big_endian(&log.data[..32]),
+        });
+    }
+
+    Decoded::Ignored
+}
+
+fn event_topic(signature: &str) -> H256 {
+    H256::from(keccak256(signature.as_bytes()))
+}
+
+fn topic_address(topic: &H256) -> Address {
+    Address::from_slice(&topic.as_bytes()[12..])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TOKEN: Address = H160([0xaa; 20]);
+    const FROM: Address = H160([0x01; 20]);
+    const TO: Address = H160([0x02; 20]);
+
+    fn word(value: u64) -> Vec<u8> {
+        let mut word = [0u8; 32];
+        U256::from(value).to_big_endian(&mut word);
+        word.to_vec()
+    }
+
+    fn log(address: Address, topics: Vec<H256>, data: Vec<u8>) -> Log {
+        Log { address, topics, data: data.into(), ..Default::default() }
+    }
+
+    fn erc20_transfer(address: Address, data: Vec<u8>) -> Log {
+        log(address, vec![event_topic(TRANSFER_EVENT), FROM.into(), TO.into()], data)
+    }
+
+    fn log_transfer(address: Address, data: Vec<u8>) -> Log {
+        log(address, vec![event_topic(LOG_TRANSFER_EVENT), NATIVE_TOKEN.into(), FROM.into(), TO.into()], data)
+    }
+
+    #[test]
+    fn decodes_erc20_transfer() {
+        let Decoded::Transfer(transfer) = decode_transfer(&erc20_transfer(TOKEN, word(7))) else {
+            panic!("not decoded");
+        };
+        assert_eq!((transfer.from, transfer.to, transfer.amount), (FROM, TO, U256::from(7)));
+    }
+
+    #[test]
+    fn ignores_native_transfer_duplicate() {
+        assert!(matches!(decode_transfer(&erc20_transfer(NATIVE_TOKEN, word(7))), Decoded::Ignored));
+    }
+
+    #[test]
+    fn decodes_native_log_transfer() {
+        // amount, input1, input2, output1, output2
+        let data = [word(9), word(100), word(50), word(91), word(59)].concat();
+        let Decoded::Transfer(transfer) = decode_transfer(&log_transfer(NATIVE_TOKEN, data)) else {
+            panic!("not decoded");
+        };
+        assert_eq!((transfer.from, transfer.to, transfer.amount), (FROM, TO, U256::from(9)));
+    }
+
+    #[test]
+    fn ignores_log_transfer_from_other_contracts() {
+        let data = [word(9), word(100), word(50), word(91), word(59)].concat();
+        assert!(matches!(decode_transfer(&log_transfer(TOKEN, data)), Decoded::Ignored));
+    }
+
+    #[test]
+    fn rejects_wrong_data_length() {
+        assert!(matches!(decode_transfer(&erc20_transfer(TOKEN, vec![0; 31])), Decoded::Malformed));
+        assert!(matches!(decode_transfer(&log_transfer(NATIVE_TOKEN, word(9))), Decoded::Malformed));
+        assert!(matches!(decode_transfer(&log(TOKEN, vec![], word(9))), Decoded::Malformed));
+    }
+}